@@ -10,27 +10,67 @@
 ///
 ///  Create a new network
 /// ex: ztnet create -s 10.10.10.10 -e 10.10.10.100 -n 24 -p true # will request creation of a ztnet
-///     return : 0, ztnetid or error
+///     with the rules of a policy (see the policy module)
+///     ztnet create ... --policy ip-only
+///     and other assign modes than IPv4 from the pools
+///     ztnet create ... --v4-assign none --v6-assign 6plane,rfc4193
+///     return : 0, ztnetid on stdout or the error code (3xx codes exit as
+///              240 plus their last two digits, see `exit_status`)
 ///     
 ///  Add a network range (1 IPv4 and 1 IPv6)
 ///     ztnet addnet -i ztnetid -s fdab:1234::1:1 -e fdab:1234::f:ff00 -n 64
 ///  
 ///  Add a route to a net via (note: carrier net needs to exist)
-///     ztnet addroute -i ztnetid 172.22.2.0 -n 24 -g 10.10.10.123
+///     ztnet addroute -i ztnetid -d 172.22.2.0 -n 24 -g 10.10.10.123
+///
+///  Remove that route again
+///     ztnet delroute -i ztnetid -d 172.22.2.0 -n 24
 ///
 ///  Athorize a client to connect (note: network has to be private)
 ///     ztnet auth -i ztnetid -c ztclientid
 ///
+///  Change settings of a network, what isn't given stays as it is
+///     ztnet set -i ztnetid [--mtu 2800] [--multicast-limit 32] [--broadcast true|false]
+///         [--passive-bridging true|false] [--dns-domain zt.example --dns-server 10.10.10.1 ... | --no-dns]
+///
 ///  Remove node from net
 ///     ztnet deauth -i ztnetid -c ztclientid
 ///     return: 0 or error
 ///
-///  Remove net from controller
-///     ztnet destroy -i ztnetid
+///  Grant a node capabilities and tag values, all defined on the network
+///     ztnet grant -i ztnetid -c ztclientid [--cap 2000 ...] [--tag 1000=1 ...]
+///
+///  Remove net from controller (--force when members are still authorized,
+///  --dry-run to only show what would go)
+///     ztnet destroy -i ztnetid [--force] [--dry-run]
 ///     return: 0 or error
 ///
-///  Start a moon for a ztnetid
-///     ztnet addmoon -i ztnetid
+///  Start a moon with the local node as root, reachable on its stable
+///  endpoints. Prints the id to `zerotier-cli orbit <id> <id>` with.
+///     ztnet addmoon -e 195.1.2.3/9993 [-e ...] [--home /var/lib/zerotier-one]
+///
+///  Set the flow rules (with capabilities and tags) of a network from a file
+///  in zerotier's rules language, see the rules module
+///     ztnet rules set -i ztnetid -f policy.ztrules
+///  or print them back as rules source
+///     ztnet rules show -i ztnetid
+///  Run the test cases of a rules file, offline, see the simulate module
+///     ztnet rules test -f policy.ztrules -c policy.cases
+///     return: 0, or 206 when a case fails (204 and 205 when the rules or
+///     the cases don't parse)
+///
+///  Show the pools of a network, how full they are and who has which
+///  address, pin an address (the next free one without -a) on a member, or
+///  take one away again
+///     ztnet ip list -i ztnetid
+///     ztnet ip assign -i ztnetid -c ztclientid [-a 10.10.10.20 | --ipv6]
+///     ztnet ip release -i ztnetid -c ztclientid -a 10.10.10.20
+///  and the RFC4193 and 6plane addresses a node will have on the network
+///     ztnet ip predict -i ztnetid -c ztclientid
+///
+///  Serve all of this as a REST api (see the server module), for bearer
+///  tokens signed with a secret or by the holder of an RSA or EC key
+///     ztnet serve -l 127.0.0.1:8080 (--jwt-secret s | --jwt-rsa-key pem | --jwt-ec-key pem)
 ///
 extern crate clap;
 extern crate failure;
@@ -41,10 +81,15 @@ use ztproxy::*;
 
 // use std::net::IpAddr;
 use failure::Error;
+use ztproxy::commands::{ControllerClient, DEFAULT_BASE_URL};
+
+use ztproxy::moon;
+
+use std::path::Path;
 
 // use std::error::Error;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgGroup, SubCommand};
 
 fn get_params() -> clap::ArgMatches<'static> {
     let matches = App::new("ZeroTier proxy")
@@ -52,6 +97,27 @@ fn get_params() -> clap::ArgMatches<'static> {
         .author("Jan De Landtsheer <jan@threefoldtech.com>")
         .about("cli and api to control local zerotier daemon")
         .usage("ztproxy <COMMAND> [params]\n    Use --help (-h) to see your options")
+        .arg(
+            Arg::with_name("url")
+                .short("u")
+                .long("url")
+                .takes_value(true)
+                .global(true)
+                .help("Base url of the controller, default http://127.0.0.1:9993"),
+        ).arg(
+            Arg::with_name("token")
+                .short("t")
+                .long("token")
+                .takes_value(true)
+                .global(true)
+                .help("Auth token of the controller, instead of reading it from disk"),
+        ).arg(
+            Arg::with_name("token-file")
+                .long("token-file")
+                .takes_value(true)
+                .global(true)
+                .help("File with the auth token, instead of looking for one"),
+        )
         .subcommand(
             SubCommand::with_name("create")
                 .about("Create a new network")
@@ -90,6 +156,22 @@ fn get_params() -> clap::ArgMatches<'static> {
                         .takes_value(false)
                         .required(false)
                         .help("Is that network private"),
+                ).arg(
+                    Arg::with_name("v4-assign")
+                        .long("v4-assign")
+                        .takes_value(true)
+                        .help("IPv4 assign mode: zt (the default) or none"),
+                ).arg(
+                    Arg::with_name("v6-assign")
+                        .long("v6-assign")
+                        .takes_value(true)
+                        .help("IPv6 assign modes, comma separated: zt, 6plane, rfc4193, or none"),
+                ).arg(
+                    Arg::with_name("policy")
+                        .long("policy")
+                        .takes_value(true)
+                        .possible_values(&policy::NAMES)
+                        .help("Rules, capabilities and tags of a ready-made policy"),
                 ),
         ).subcommand(
             SubCommand::with_name("addsubnet")
@@ -127,7 +209,14 @@ fn get_params() -> clap::ArgMatches<'static> {
             SubCommand::with_name("addroute")
                 .about("Add a route for a network")
                 .arg(
-                    Arg::with_name("destination net")
+                    Arg::with_name("nwid")
+                        .short("i")
+                        .long("nwid")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Zerotier address of network"),
+                ).arg(
+                    Arg::with_name("destnet")
                         .short("d")
                         .long("destnet")
                         .takes_value(true)
@@ -148,6 +237,31 @@ fn get_params() -> clap::ArgMatches<'static> {
                         .required(true)
                         .help("Through which ip to reach that net"),
                 ),
+        ).subcommand(
+            SubCommand::with_name("delroute")
+                .about("Remove a route from a network")
+                .arg(
+                    Arg::with_name("nwid")
+                        .short("i")
+                        .long("nwid")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Zerotier address of network"),
+                ).arg(
+                    Arg::with_name("destnet")
+                        .short("d")
+                        .long("destnet")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Subnet of the route"),
+                ).arg(
+                    Arg::with_name("mask")
+                        .short("n")
+                        .long("mask")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Network mask in bits "),
+                ),
         ).subcommand(
             SubCommand::with_name("auth")
                 .about("Authorize a client node")
@@ -166,6 +280,88 @@ fn get_params() -> clap::ArgMatches<'static> {
                         .required(true)
                         .help("Zerotier client id"),
                 ),
+        ).subcommand(
+            SubCommand::with_name("grant")
+                .about("Grant a client node capabilities and tags")
+                .arg(
+                    Arg::with_name("ztnetid")
+                        .short("i")
+                        .long("ztnetid")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Zerotier address of network"),
+                ).arg(
+                    Arg::with_name("clientid")
+                        .short("c")
+                        .long("clid")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Zerotier client id"),
+                ).arg(
+                    Arg::with_name("cap")
+                        .long("cap")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Id of a capability of the network"),
+                ).arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Tag id and value, as id=value"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("set")
+                .about("Change the settings of a network, the others stay")
+                .arg(
+                    Arg::with_name("ztnetid")
+                        .short("i")
+                        .long("ztnetid")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Zerotier address of network"),
+                ).arg(
+                    Arg::with_name("mtu")
+                        .long("mtu")
+                        .takes_value(true)
+                        .help("MTU of the network, 1280 to 10000"),
+                ).arg(
+                    Arg::with_name("multicast-limit")
+                        .long("multicast-limit")
+                        .takes_value(true)
+                        .help("Members a multicast goes to at most"),
+                ).arg(
+                    Arg::with_name("broadcast")
+                        .long("broadcast")
+                        .takes_value(true)
+                        .possible_values(&["true", "false"])
+                        .help("Let members broadcast (ff:ff:ff:ff:ff:ff)"),
+                ).arg(
+                    Arg::with_name("passive-bridging")
+                        .long("passive-bridging")
+                        .takes_value(true)
+                        .possible_values(&["true", "false"])
+                        .help("Let any member bridge, without being an active bridge"),
+                ).arg(
+                    Arg::with_name("dns-domain")
+                        .long("dns-domain")
+                        .takes_value(true)
+                        .help("Search domain pushed to the members"),
+                ).arg(
+                    Arg::with_name("dns-server")
+                        .long("dns-server")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Name server pushed to the members, replaces the ones set"),
+                ).arg(
+                    Arg::with_name("no-dns")
+                        .long("no-dns")
+                        .conflicts_with_all(&["dns-domain", "dns-server"])
+                        .help("Push no domain nor name servers"),
+                ),
         ).subcommand(
             SubCommand::with_name("deauth")
                 .about("Un-authorize a client node")
@@ -195,16 +391,255 @@ fn get_params() -> clap::ArgMatches<'static> {
                         .required(true)
                         .help("Zerotier address of network"),
                 ).arg(
-                    Arg::with_name("need to be sure")
+                    Arg::with_name("force")
                         .long("force")
+                        .required(false)
+                        .help("No force ? no delete of nets with authorized members!"),
+                ).arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .required(false)
+                        .help("Only show what would be removed"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("addmoon")
+                .about("Make the local node the root of a moon")
+                .arg(
+                    Arg::with_name("endpoint")
+                        .short("e")
+                        .long("endpoint")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
                         .required(true)
-                        .help("No force ? no delete!"),
+                        .help("Stable endpoint ip/port of this node"),
+                ).arg(
+                    Arg::with_name("home")
+                        .long("home")
+                        .takes_value(true)
+                        .default_value("/var/lib/zerotier-one")
+                        .help("Zerotier home directory"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("serve")
+                .about("Serve the REST api")
+                .arg(
+                    Arg::with_name("listen")
+                        .short("l")
+                        .long("listen")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8080")
+                        .help("Address to listen on"),
+                ).arg(
+                    Arg::with_name("home")
+                        .long("home")
+                        .takes_value(true)
+                        .default_value("/var/lib/zerotier-one")
+                        .help("Zerotier home directory, for the moon"),
+                ).arg(
+                    Arg::with_name("jwt-secret")
+                        .long("jwt-secret")
+                        .takes_value(true)
+                        .help("Accept HS256 tokens signed with this secret"),
+                ).arg(
+                    Arg::with_name("jwt-rsa-key")
+                        .long("jwt-rsa-key")
+                        .takes_value(true)
+                        .help("Accept RS256 tokens, PEM file with the public key"),
+                ).arg(
+                    Arg::with_name("jwt-ec-key")
+                        .long("jwt-ec-key")
+                        .takes_value(true)
+                        .help("Accept ES256 tokens, PEM file with the public key"),
+                ).group(
+                    ArgGroup::with_name("jwt")
+                        .args(&["jwt-secret", "jwt-rsa-key", "jwt-ec-key"])
+                        .required(true),
+                ),
+        ).subcommand(
+            SubCommand::with_name("ip")
+                .about("Addresses of the members of a network")
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Pools, their use, and the addresses of all members")
+                        .arg(
+                            Arg::with_name("nwid")
+                                .short("i")
+                                .long("nwid")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Zerotier address of network"),
+                        ),
+                ).subcommand(
+                    SubCommand::with_name("assign")
+                        .about("Give a member an address from the pools")
+                        .arg(
+                            Arg::with_name("nwid")
+                                .short("i")
+                                .long("nwid")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Zerotier address of network"),
+                        ).arg(
+                            Arg::with_name("clientid")
+                                .short("c")
+                                .long("clid")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Zerotier client id"),
+                        ).arg(
+                            Arg::with_name("address")
+                                .short("a")
+                                .long("address")
+                                .takes_value(true)
+                                .help("The address, instead of the next free one"),
+                        ).arg(
+                            Arg::with_name("ipv6")
+                                .long("ipv6")
+                                .conflicts_with("address")
+                                .help("The next free IPv6 address, instead of IPv4"),
+                        ),
+                ).subcommand(
+                    SubCommand::with_name("predict")
+                        .about("The RFC4193 and 6plane addresses a member gets, joined or not")
+                        .arg(
+                            Arg::with_name("nwid")
+                                .short("i")
+                                .long("nwid")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Zerotier address of network"),
+                        ).arg(
+                            Arg::with_name("clientid")
+                                .short("c")
+                                .long("clid")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Zerotier client id"),
+                        ),
+                ).subcommand(
+                    SubCommand::with_name("release")
+                        .about("Take an address away from a member")
+                        .arg(
+                            Arg::with_name("nwid")
+                                .short("i")
+                                .long("nwid")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Zerotier address of network"),
+                        ).arg(
+                            Arg::with_name("clientid")
+                                .short("c")
+                                .long("clid")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Zerotier client id"),
+                        ).arg(
+                            Arg::with_name("address")
+                                .short("a")
+                                .long("address")
+                                .takes_value(true)
+                                .required(true)
+                                .help("The address to release"),
+                        ),
+                ),
+        ).subcommand(
+            SubCommand::with_name("rules")
+                .about("Flow rules of a network")
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Compile a rules file and make it the network's rules")
+                        .arg(
+                            Arg::with_name("nwid")
+                                .short("i")
+                                .long("nwid")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Zerotier address of network"),
+                        ).arg(
+                            Arg::with_name("file")
+                                .short("f")
+                                .long("file")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Rules in zerotier's rules language"),
+                        ),
+                ).subcommand(
+                    SubCommand::with_name("test")
+                        .about("Run frames through a rules file and check what becomes of them")
+                        .arg(
+                            Arg::with_name("file")
+                                .short("f")
+                                .long("file")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Rules in zerotier's rules language"),
+                        ).arg(
+                            Arg::with_name("cases")
+                                .short("c")
+                                .long("cases")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Test cases, one verdict and frame each"),
+                        ),
+                ).subcommand(
+                    SubCommand::with_name("show")
+                        .about("Print the network's rules in zerotier's rules language")
+                        .arg(
+                            Arg::with_name("nwid")
+                                .short("i")
+                                .long("nwid")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Zerotier address of network"),
+                        ),
                 ),
         ).get_matches();
     matches
 }
 
-fn main() -> Result<(), Error> {
+/// Every command that talks to the controller goes through here, so they
+/// all honour `--url`, `--token` and `--token-file`. Without those the token
+/// is looked for like zerotier-cli does (`ZT_TOKEN`, `~/.zeroTierOneAuthToken`,
+/// then the zerotier homes). With a token but no identity at hand, the
+/// controller's address gets asked from the daemon when it's needed.
+fn client(m: &clap::ArgMatches) -> Result<ControllerClient, Error> {
+    let auth = match m.value_of("token") {
+        Some(t) => Auth {
+            serverid: None,
+            auth_token: t.to_owned(),
+        },
+        None => Auth::discover(m.value_of("token-file").map(Path::new)).map_err(ZTError::from)?,
+    };
+    let url = m.value_of("url").unwrap_or(DEFAULT_BASE_URL);
+    ControllerClient::with(url, auth, None, None)
+}
+
+/// Failures end up on stderr, and a `ZTError` code becomes our exit status.
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        let code = match e.downcast_ref::<ZTError>() {
+            Some(zte) => exit_status(zte.code()),
+            None => 1,
+        };
+        std::process::exit(code);
+    }
+}
+
+/// An exit status only goes up to 255. The 1xx library and 2xx cli codes
+/// exit as they are, the 3xx api/http codes as 240 plus their last two
+/// digits (301 exits as 241, 313 as 253), anything else as 255. The
+/// message always carries the full code.
+fn exit_status(code: i32) -> i32 {
+    match code {
+        1..=239 => code,
+        300..=314 => 240 + code - 300,
+        _ => 255,
+    }
+}
+
+fn run() -> Result<(), Error> {
     let matches = get_params();
     let mut p: bool = false;
     match matches.subcommand() {
@@ -217,37 +652,300 @@ fn main() -> Result<(), Error> {
             if m.is_present("private") {
                 p = true;
             }
-            let r = RootInterface::with(
-                Some(name.to_string()),
-                p,
-                start.parse().unwrap(),
-                end.parse().unwrap(),
-                mask.parse().unwrap(),
-                None,
-            );
+            let mut b = RootInterface::builder()
+                .name(name)
+                .private(p)
+                .parse_pool(start, end, mask);
+            if let Some(mode) = m.value_of("v4-assign") {
+                b = b.parse_v4_assign_mode(mode);
+            }
+            if let Some(mode) = m.value_of("v6-assign") {
+                b = b.parse_v6_assign_mode(mode);
+            }
+            let mut r = b.build()?;
+            if let Some(name) = m.value_of("policy") {
+                policy::policy(name)?.apply_to(&mut r);
+            }
 
-            let j = serde_json::to_string(&r)?;
-            println!("{}", j);
-            //return Ok(commands::newnet(&r));
+            let net = client(m)?.new_network(&r)?;
+            // only the nwid goes to stdout, so scripts can $(ztproxy create ...)
+            match net.nwid {
+                Some(nwid) => println!("{}", nwid),
+                None => {
+                    return Err(ZTError::new(202, "controller returned a network without nwid").into())
+                }
+            }
         }
 
-        // Add a subnet to an nwid
+        // Add a subnet to an nwid, keeping what's already there
         ("addsubnet", Some(m)) => {
             let start = m.value_of("start").unwrap();
             let end = m.value_of("end").unwrap();
             let mask = m.value_of("mask").unwrap();
-            let nwid = m.value_of("nwid").unwrap();
-            let mut r = RootInterface::with(
-                None,
-                p,
-                start.parse().unwrap(),
-                end.parse().unwrap(),
-                mask.parse().unwrap(),
-                None,
+            let nwid: NetworkId = m.value_of("nwid").unwrap().parse()?;
+            let (start, end, mask) = (parse_addr(start)?, parse_addr(end)?, parse_mask(mask)?);
+            let c = client(m)?;
+            let mut net = c.get_network(nwid)?;
+            net.add_subnet(start, end, mask)?;
+            c.update_network_checked(&net)?;
+        }
+
+        // Route a net through a gateway inside one of our carrying nets
+        ("addroute", Some(m)) => {
+            let nwid: NetworkId = m.value_of("nwid").unwrap().parse()?;
+            let dest = m.value_of("destnet").unwrap();
+            let mask = m.value_of("mask").unwrap();
+            let gw = m.value_of("gateway").unwrap();
+            let (target, gw) = (parse_net(dest, mask)?, parse_addr(gw)?);
+            let c = client(m)?;
+            let mut net = c.get_network(nwid)?;
+            net.add_route(target, Some(gw))?;
+            c.update_network_checked(&net)?;
+        }
+
+        ("delroute", Some(m)) => {
+            let nwid: NetworkId = m.value_of("nwid").unwrap().parse()?;
+            let dest = m.value_of("destnet").unwrap();
+            let mask = m.value_of("mask").unwrap();
+            let target = parse_net(dest, mask)?;
+            let c = client(m)?;
+            let mut net = c.get_network(nwid)?;
+            net.del_route(target)?;
+            c.update_network_checked(&net)?;
+        }
+
+        // Let a node in on a private network
+        ("auth", Some(m)) => {
+            let nwid: NetworkId = m.value_of("ztnetid").unwrap().parse()?;
+            let clid: NodeAddress = m.value_of("clientid").unwrap().parse()?;
+            let member = client(m)?.authorize_member(nwid, clid)?;
+            println!("{}", serde_json::to_string(&member)?);
+        }
+
+        // All in one update, which fails when the network lacks any of them
+        ("grant", Some(m)) => {
+            let nwid: NetworkId = m.value_of("ztnetid").unwrap().parse()?;
+            let clid: NodeAddress = m.value_of("clientid").unwrap().parse()?;
+            let c = client(m)?;
+            let mut member = c.get_member(nwid, clid)?;
+            for cap in m.values_of("cap").into_iter().flatten() {
+                let cap = cap.parse().map_err(|_| ZTError::new(207, &format!("capability {} is not a number", cap)))?;
+                member.grant_capability(cap);
+            }
+            for tag in m.values_of("tag").into_iter().flatten() {
+                let (id, value) = match tag.split_once('=').map(|(i, v)| (i.parse(), v.parse())) {
+                    Some((Ok(i), Ok(v))) => (i, v),
+                    _ => return Err(ZTError::new(203, &format!("tag {} is not id=value", tag)).into()),
+                };
+                member.set_tag(id, value);
+            }
+            let member = c.update_member_checked(&member)?;
+            println!("{}", serde_json::to_string(&member)?);
+        }
+        // Read, change what was asked, write back unless the network changed
+        // meanwhile (as far as update_network_checked can tell)
+        ("set", Some(m)) => {
+            let nwid: NetworkId = m.value_of("ztnetid").unwrap().parse()?;
+            let c = client(m)?;
+            let mut net = c.get_network(nwid)?;
+            if let Some(mtu) = m.value_of("mtu") {
+                net.set_mtu(mtu.parse().map_err(|_| ZTError::new(208, &format!("invalid mtu {}", mtu)))?)?;
+            }
+            if let Some(limit) = m.value_of("multicast-limit") {
+                net.multicast_limit = limit
+                    .parse()
+                    .map_err(|_| ZTError::new(209, &format!("invalid multicast limit {}", limit)))?;
+            }
+            if let Some(b) = m.value_of("broadcast") {
+                net.enable_broadcast = b.parse()?;
+            }
+            if let Some(b) = m.value_of("passive-bridging") {
+                net.allow_passive_bridging = b.parse()?;
+            }
+            if m.is_present("no-dns") {
+                net.dns = Dns::default();
+            }
+            if let Some(domain) = m.value_of("dns-domain") {
+                net.dns.domain = domain.to_owned();
+            }
+            if let Some(servers) = m.values_of("dns-server") {
+                net.dns.servers = servers.map(parse_addr).collect::<Result<_, _>>()?;
+            }
+            let net = c.update_network_checked(&net)?;
+            println!("{}", serde_json::to_string(&net)?);
+        }
+
+        // And kick it out again
+        ("deauth", Some(m)) => {
+            let nwid: NetworkId = m.value_of("ztnetid").unwrap().parse()?;
+            let clid: NodeAddress = m.value_of("clientid").unwrap().parse()?;
+            let member = client(m)?.deauthorize_member(nwid, clid)?;
+            println!("{}", serde_json::to_string(&member)?);
+        }
+        // Remove a network from the controller, members and all
+        ("destroy", Some(m)) => {
+            let nwid: NetworkId = m.value_of("ztnetid").unwrap().parse()?;
+            let c = client(m)?;
+            let net = c.get_network(nwid)?;
+            let members = c.list_members(nwid)?;
+            let mut authorized = 0;
+            for id in &members {
+                if c.get_member(nwid, *id)?.authorized {
+                    authorized += 1;
+                }
+            }
+            println!(
+                "network {} ({}): {} members, {} authorized",
+                nwid,
+                net.name.unwrap_or_default(),
+                members.len(),
+                authorized
             );
-            r.nwid = Some(nwid.to_string());
-            //return Ok(commands::update_net(&r));
+            // a dry run refuses just like the real thing would
+            if authorized > 0 && !m.is_present("force") {
+                let msg = format!("network still has {} authorized members, use --force", authorized);
+                if m.is_present("dry-run") {
+                    return Err(ZTError::new(201, &format!("dry run, would refuse: {}", msg)).into());
+                }
+                return Err(ZTError::new(201, &msg).into());
+            }
+            if m.is_present("dry-run") {
+                println!("dry run, nothing removed");
+                return Ok(());
+            }
+            c.delete_network(nwid)?;
+            println!("network {} removed", nwid);
         }
+        // Sign a moon with the local node as root and hand it to the daemon.
+        // The definition with its signing key stays in <home>/moon.json, so
+        // running this again updates the same moon.
+        ("addmoon", Some(m)) => {
+            let home = Path::new(m.value_of("home").unwrap());
+            let mut endpoints = Vec::new();
+            for ep in m.values_of("endpoint").unwrap() {
+                endpoints.push(moon::parse_endpoint(ep)?);
+            }
+            let moon = moon::deploy_local(home, &endpoints)?;
+            println!("{}", moon.seed());
+        }
+        // Let other services do all of the above over http
+        ("serve", Some(m)) => {
+            let home = Path::new(m.value_of("home").unwrap()).to_path_buf();
+            let jwt = if let Some(secret) = m.value_of("jwt-secret") {
+                jwt::JwtAuth::hs256(secret.as_bytes())
+            } else if let Some(pem) = m.value_of("jwt-rsa-key") {
+                jwt::JwtAuth::rs256(&std::fs::read(pem)?)?
+            } else {
+                jwt::JwtAuth::es256(&std::fs::read(m.value_of("jwt-ec-key").unwrap())?)?
+            };
+            let api = server::ApiServer::new(client(m)?, home, jwt);
+            api.serve(m.value_of("listen").unwrap())?;
+        }
+        // The controller only keeps the assignments on the members, so that's
+        // where an address goes or is taken from
+        ("ip", Some(i)) => match i.subcommand() {
+            ("list", Some(m)) => {
+                let nwid: NetworkId = m.value_of("nwid").unwrap().parse()?;
+                let ipam = client(m)?.ipam(nwid)?;
+                for u in ipam.utilization() {
+                    println!("{}", u);
+                }
+                for (ip, members) in ipam.used() {
+                    for member in members {
+                        println!("{} {}", ip, member);
+                    }
+                }
+                for p in ipam.problems() {
+                    eprintln!("{}", p);
+                }
+            }
+            ("assign", Some(m)) => {
+                let nwid: NetworkId = m.value_of("nwid").unwrap().parse()?;
+                let clid: NodeAddress = m.value_of("clientid").unwrap().parse()?;
+                let c = client(m)?;
+                let mut ipam = c.ipam(nwid)?;
+                let ip = match m.value_of("address") {
+                    Some(a) => {
+                        let ip = parse_addr(a)?;
+                        ipam.allocate(clid, ip)?;
+                        ip
+                    }
+                    None => ipam.allocate_next(clid, m.is_present("ipv6"))?,
+                };
+                let mut member = c.get_member(nwid, clid)?;
+                member.ip_assignments.push(ip);
+                c.update_member(&member)?;
+                println!("{}", ip);
+            }
+            // Needs no controller, the addresses follow from the ids
+            ("predict", Some(m)) => {
+                let nwid: NetworkId = m.value_of("nwid").unwrap().parse()?;
+                let clid: NodeAddress = m.value_of("clientid").unwrap().parse()?;
+                println!("rfc4193 {}", nwid.rfc4193_address(clid));
+                println!("6plane  {} ({})", nwid.sixplane_address(clid), nwid.sixplane_member_prefix(clid));
+            }
+            ("release", Some(m)) => {
+                let nwid: NetworkId = m.value_of("nwid").unwrap().parse()?;
+                let clid: NodeAddress = m.value_of("clientid").unwrap().parse()?;
+                let ip = parse_addr(m.value_of("address").unwrap())?;
+                let c = client(m)?;
+                c.ipam(nwid)?.release(clid, ip)?;
+                let mut member = c.get_member(nwid, clid)?;
+                member.ip_assignments.retain(|a| *a != ip);
+                c.update_member(&member)?;
+            }
+            _ => println!("ip what? \n{}", i.usage()),
+        },
+        // Rules, capabilities and tags all come from the one file, and
+        // replace what the network had
+        ("rules", Some(r)) => match r.subcommand() {
+            ("set", Some(m)) => {
+                let nwid: NetworkId = m.value_of("nwid").unwrap().parse()?;
+                let file = m.value_of("file").unwrap();
+                let compiled = match rules::compile(&std::fs::read_to_string(file)?) {
+                    Ok(c) => c,
+                    Err(e) => return Err(ZTError::new(204, &format!("{}:{}", file, e)).into()),
+                };
+                let c = client(m)?;
+                let mut net = c.get_network(nwid)?;
+                compiled.apply_to(&mut net);
+                c.update_network_checked(&net)?;
+            }
+            // Never talks to the controller, so it runs in CI as well
+            ("test", Some(m)) => {
+                let file = m.value_of("file").unwrap();
+                let compiled = match rules::compile(&std::fs::read_to_string(file)?) {
+                    Ok(c) => c,
+                    Err(e) => return Err(ZTError::new(204, &format!("{}:{}", file, e)).into()),
+                };
+                let cases_file = m.value_of("cases").unwrap();
+                let cases = match simulate::parse_cases(&std::fs::read_to_string(cases_file)?, &compiled) {
+                    Ok(c) => c,
+                    Err(e) => return Err(ZTError::new(205, &format!("{}:{}", cases_file, e)).into()),
+                };
+                let mut net = RootInterface::default();
+                compiled.apply_to(&mut net);
+                let mut failed = 0;
+                for (case, outcome) in simulate::run_cases(&net, &cases) {
+                    if case.passes(&outcome) {
+                        println!("ok   {}:{}: {}", cases_file, case.line, outcome);
+                    } else {
+                        failed += 1;
+                        println!("FAIL {}:{}: expected {}, got {}", cases_file, case.line, case.verdict, outcome);
+                    }
+                }
+                if failed > 0 {
+                    let msg = format!("{} of {} cases failed", failed, cases.len());
+                    return Err(ZTError::new(206, &msg).into());
+                }
+            }
+            ("show", Some(m)) => {
+                let nwid: NetworkId = m.value_of("nwid").unwrap().parse()?;
+                let net = client(m)?.get_network(nwid)?;
+                print!("{}", rules::decompile(&net));
+            }
+            _ => println!("rules what? \n{}", r.usage()),
+        },
         ("", None) => println!("No command entered \n{}",matches.usage()),
         //println!("no command used"),
         _ => println!("unknown command! \n{}",matches.usage()),
@@ -1,42 +1,407 @@
 extern crate failure;
 extern crate reqwest;
 use failure::Error;
-use super::{RootInterface, Auth, serde_json};
+use super::{RootInterface, Member, Auth, NetworkId, NodeAddress, ZTError, serde_json};
+use super::ipam::Ipam;
+#[cfg(test)]
+use super::mock;
+use std::time::Duration;
 
-const BASE_URL: &'static str = "http://127.0.0.1:9993";
+/// Where a zerotier daemon listens when nothing else is configured
+pub const DEFAULT_BASE_URL: &str = "http://127.0.0.1:9993";
 
-pub fn new_network(r: RootInterface, auth: Auth) -> Result<(RootInterface), Error> {
-    let net_url: String = format!("{}/network",BASE_URL).to_owned();
-    let installed_net = call_zt_get(serde_json::to_string(&r)?)?;
-    Ok(serde_json::from_value(installed_net)?)
-}
-    
-fn get_network(i: String) -> Result<RootInterface,Error> {
-    let net_url: String = format!("{}/network/{}",BASE_URL,i).to_owned();
-    let v: serde_json::Value = call_zt_get(net_url)?;
-    println!("{:?}",v);
-    let r: RootInterface = serde_json::from_value(v)?;
-    Ok(r)
+/// The local zerotier daemon only answers requests that carry its token
+const AUTH_HEADER: &str = "X-ZT1-Auth";
+
+/// A client for one zerotier controller. It keeps the base url and the
+/// auth of that controller, so one process can drive several of them, be
+/// it on other ports, in containers or on remote hosts.
+/// The network, member and status operations are all methods on it.
+pub struct ControllerClient {
+    base_url: String,
+    auth: Auth,
+    timeout: Option<Duration>,
+    proxy: Option<String>,
+    http: reqwest::Client,
 }
 
-pub fn update_network(r: RootInterface) -> Result<RootInterface,Error> {
-    let ret = RootInterface::default();
-    Ok(ret)
+impl ControllerClient {
+    /// A client for the daemon on `localhost:9993`
+    pub fn new(auth: Auth) -> Result<Self, Error> {
+        ControllerClient::with(DEFAULT_BASE_URL, auth, None, None)
+    }
+
+    /// A client for a controller elsewhere. A `None` timeout keeps the
+    /// reqwest default, the proxy is used for every request when given.
+    pub fn with(
+        base_url: &str,
+        auth: Auth,
+        timeout: Option<Duration>,
+        proxy: Option<&str>,
+    ) -> Result<Self, Error> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(t) = timeout {
+            builder = builder.timeout(t);
+        }
+        if let Some(p) = proxy {
+            builder = builder.proxy(reqwest::Proxy::all(p)?);
+        }
+        Ok(ControllerClient {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            auth,
+            timeout,
+            proxy: proxy.map(|p| p.to_owned()),
+            http: builder.build()?,
+        })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    /// Status of the daemon itself (address, version, online, ...)
+    pub fn status(&self) -> Result<serde_json::Value, Error> {
+        self.call_zt_get("/status")
+    }
+
+    /// Status of the embedded controller
+    pub fn controller_status(&self) -> Result<serde_json::Value, Error> {
+        self.call_zt_get("/controller")
+    }
+
+    /// The nwids of all networks this controller manages
+    pub fn list_networks(&self) -> Result<Vec<NetworkId>, Error> {
+        let v: serde_json::Value = self.call_zt_get("/controller/network")?;
+        Ok(serde_json::from_value(v)?)
+    }
+
+    /// Create a new network on the controller. We post to
+    /// `<serverid>______`, the controller fills in the last 6 hex digits and
+    /// hands us back the complete network, with its freshly generated nwid.
+    /// Without a server id in our auth, we ask the daemon for its address.
+    pub fn new_network(&self, r: &RootInterface) -> Result<RootInterface, Error> {
+        let serverid: NodeAddress = match self.auth.serverid {
+            Some(s) => s,
+            None => match self.status()?["address"].as_str() {
+                Some(a) => a.parse()?,
+                None => {
+                    return Err(ZTError {
+                        code: 103i32,
+                        message: "no server id to create a network on".to_string(),
+                    }
+                    .into())
+                }
+            },
+        };
+        let path: String = format!("/controller/network/{}______", serverid);
+        let v: serde_json::Value = self.call_zt_post(&path, r)?;
+        Ok(serde_json::from_value(v)?)
+    }
+
+    /// Fetch the current configuration of a network
+    pub fn get_network(&self, nwid: NetworkId) -> Result<RootInterface, Error> {
+        let path: String = format!("/controller/network/{}", nwid);
+        let v: serde_json::Value = self.call_zt_get(&path)?;
+        Ok(serde_json::from_value(v)?)
+    }
+
+    /// Post the configuration of an existing network back to the controller.
+    /// The network needs its nwid, otherwise we don't know what to update.
+    pub fn update_network(&self, r: &RootInterface) -> Result<RootInterface, Error> {
+        let nwid = match r.nwid {
+            Some(n) => n,
+            None => {
+                return Err(ZTError {
+                    code: 104i32,
+                    message: "can't update a network without nwid".to_string(),
+                }
+                .into())
+            }
+        };
+        let path: String = format!("/controller/network/{}", nwid);
+        let v: serde_json::Value = self.call_zt_post(&path, r)?;
+        Ok(serde_json::from_value(v)?)
+    }
+
+    /// Like `update_network`, but refused when the network on the controller
+    /// changed since we read `r` from it. This is best effort: the controller
+    /// does not do conditional writes, so we compare revisions right before
+    /// posting, and a write landing between the two still gets overwritten.
+    pub fn update_network_checked(&self, r: &RootInterface) -> Result<RootInterface, Error> {
+        if let Some(nwid) = r.nwid {
+            let current = self.get_network(nwid)?;
+            if current.revision != r.revision {
+                return Err(ZTError {
+                    code: 302i32,
+                    message: format!(
+                        "network {} changed since it was read (revision {:?}, now {:?}), \
+                         read it again and retry; this check is best effort",
+                        nwid, r.revision, current.revision
+                    ),
+                }
+                .into());
+            }
+        }
+        self.update_network(r)
+    }
+
+    /// Remove a network from the controller, its members go with it
+    pub fn delete_network(&self, nwid: NetworkId) -> Result<(), Error> {
+        let path: String = format!("/controller/network/{}", nwid);
+        self.call_zt_delete(&path)?;
+        Ok(())
+    }
+
+    /// The ids of all members that ever asked to join a network
+    pub fn list_members(&self, nwid: NetworkId) -> Result<Vec<NodeAddress>, Error> {
+        let path: String = format!("/controller/network/{}/member", nwid);
+        let v: serde_json::Value = self.call_zt_get(&path)?;
+        // the controller answers with a map of member id to revision
+        let revisions: std::collections::BTreeMap<NodeAddress, u64> = serde_json::from_value(v)?;
+        Ok(revisions.into_keys().collect())
+    }
+
+    /// Fetch one member of a network
+    pub fn get_member(&self, nwid: NetworkId, id: NodeAddress) -> Result<Member, Error> {
+        let path: String = format!("/controller/network/{}/member/{}", nwid, id);
+        let v: serde_json::Value = self.call_zt_get(&path)?;
+        Ok(serde_json::from_value(v)?)
+    }
+
+    /// Post a member back to the controller, the member needs its nwid and id
+    pub fn update_member(&self, m: &Member) -> Result<Member, Error> {
+        let (nwid, id) = match (&m.nwid, &m.id) {
+            (Some(n), Some(i)) => (n, i),
+            _ => {
+                return Err(ZTError {
+                    code: 105i32,
+                    message: "can't update a member without nwid and id".to_string(),
+                }
+                .into())
+            }
+        };
+        let path: String = format!("/controller/network/{}/member/{}", nwid, id);
+        let v: serde_json::Value = self.call_zt_post(&path, m)?;
+        Ok(serde_json::from_value(v)?)
+    }
+
+    /// The address use of a network, from the network and all its members
+    pub fn ipam(&self, nwid: NetworkId) -> Result<Ipam, Error> {
+        let net = self.get_network(nwid)?;
+        let mut members = Vec::new();
+        for id in self.list_members(nwid)? {
+            members.push(self.get_member(nwid, id)?);
+        }
+        Ok(Ipam::new(&net, &members))
+    }
+
+    /// Like `update_member`, but only when every capability and tag of the
+    /// member is defined on its network
+    pub fn update_member_checked(&self, m: &Member) -> Result<Member, Error> {
+        if let Some(nwid) = m.nwid {
+            self.get_network(nwid)?.check_member(m)?;
+        }
+        self.update_member(m)
+    }
+
+    /// Grant a member a capability of its network
+    pub fn grant_capability(&self, nwid: NetworkId, id: NodeAddress, cap: u32) -> Result<Member, Error> {
+        let mut m = self.get_member(nwid, id)?;
+        m.grant_capability(cap);
+        self.update_member_checked(&m)
+    }
+
+    /// Take a capability away from a member
+    pub fn revoke_capability(&self, nwid: NetworkId, id: NodeAddress, cap: u32) -> Result<Member, Error> {
+        let mut m = self.get_member(nwid, id)?;
+        m.revoke_capability(cap);
+        self.update_member(&m)
+    }
+
+    /// Set a member's value of a tag of its network
+    pub fn set_member_tag(&self, nwid: NetworkId, id: NodeAddress, tag: u32, value: u32) -> Result<Member, Error> {
+        let mut m = self.get_member(nwid, id)?;
+        m.set_tag(tag, value);
+        self.update_member_checked(&m)
+    }
+
+    /// Allow a node to send and receive on a private network
+    pub fn authorize_member(&self, nwid: NetworkId, id: NodeAddress) -> Result<Member, Error> {
+        self.set_authorized(nwid, id, true)
+    }
+
+    /// Kick a node off a private network, it stays known as a member
+    pub fn deauthorize_member(&self, nwid: NetworkId, id: NodeAddress) -> Result<Member, Error> {
+        self.set_authorized(nwid, id, false)
+    }
+
+    /// Forget about a member altogether
+    pub fn delete_member(&self, nwid: NetworkId, id: NodeAddress) -> Result<(), Error> {
+        let path: String = format!("/controller/network/{}/member/{}", nwid, id);
+        self.call_zt_delete(&path)?;
+        Ok(())
+    }
+
+    /// The controller merges what we post, so we only send the flag
+    fn set_authorized(&self, nwid: NetworkId, id: NodeAddress, a: bool) -> Result<Member, Error> {
+        let path: String = format!("/controller/network/{}/member/{}", nwid, id);
+        let v: serde_json::Value =
+            self.call_zt_post(&path, &serde_json::json!({ "authorized": a }))?;
+        Ok(serde_json::from_value(v)?)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    fn call_zt_get(&self, path: &str) -> Result<serde_json::Value, Error> {
+        let resp = self
+            .http
+            .get(&*self.url(path))
+            .header(AUTH_HEADER, &*self.auth.auth_token)
+            .send()?;
+        read_zt_response(resp)
+    }
+
+    fn call_zt_post<T: serde::Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<serde_json::Value, Error> {
+        let resp = self
+            .http
+            .post(&*self.url(path))
+            .header(AUTH_HEADER, &*self.auth.auth_token)
+            .json(body)
+            .send()?;
+        read_zt_response(resp)
+    }
+
+    fn call_zt_delete(&self, path: &str) -> Result<serde_json::Value, Error> {
+        let resp = self
+            .http
+            .delete(&*self.url(path))
+            .header(AUTH_HEADER, &*self.auth.auth_token)
+            .send()?;
+        read_zt_response(resp)
+    }
 }
 
-fn call_zt_get(u: String) -> Result<serde_json::Value, Error> {
-    let v = reqwest::get(&*u)?.text()?;
-    let v: serde_json::Value = serde_json::from_str(&v)?;
+/// The daemon answers errors with an empty body or plain text, so we
+/// check the status before trying to make JSON out of it. A 404 is a
+/// network or member that isn't there, anything else the controller failing.
+fn read_zt_response(mut resp: reqwest::Response) -> Result<serde_json::Value, Error> {
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(ZTError {
+            code: 303i32,
+            message: format!("controller has nothing at {}", resp.url()),
+        }
+        .into());
+    }
+    if !resp.status().is_success() {
+        return Err(ZTError {
+            code: 301i32,
+            message: format!("controller answered {} for {}", resp.status(), resp.url()),
+        }
+        .into());
+    }
+    let v: serde_json::Value = resp.json()?;
     Ok(v)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use mock::MockController;
+    use super::super::{Capability, TagDefinition};
+
     #[test]
-    fn test_get(){
-        let v = get_network("65a8d1a59587fee4".to_owned());
+    fn test_get() -> Result<(), Error> {
+        let mock = MockController::start("8056c2e21c", "sikrit")?;
+        let client = mock.client()?;
+        let e = client.get_network("8056c2e21c000001".parse()?).unwrap_err();
+        assert_eq!(e.downcast_ref::<ZTError>().unwrap().code(), 303);
+        let net = client.new_network(&RootInterface::default())?;
+        let nwid = net.nwid.unwrap();
+        assert_eq!(nwid.controller(), "8056c2e21c".parse()?);
+        let v = client.get_network(nwid)?;
         println!("{:?}",v);
-        assert!(true);
+        assert_eq!(v.name, Some("tfnet".to_owned()));
+        assert_eq!(client.list_networks()?, vec![nwid]);
+        Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_wrong_token() -> Result<(), Error> {
+        let mock = MockController::start("8056c2e21c", "sikrit")?;
+        let mut auth = mock.auth();
+        auth.auth_token = "guess".to_owned();
+        let client = ControllerClient::with(mock.base_url(), auth, None, None)?;
+        let e = client.status().unwrap_err();
+        assert_eq!(e.downcast_ref::<ZTError>().unwrap().code(), 301);
+        Ok(())
+    }
+
+    #[test]
+    fn test_members() -> Result<(), Error> {
+        let mock = MockController::start("8056c2e21c", "sikrit")?;
+        let client = mock.client()?;
+        let nwid = client.new_network(&RootInterface::default())?.nwid.unwrap();
+        let id: NodeAddress = "deadbeef00".parse()?;
+        mock.join(nwid, id);
+        assert_eq!(client.list_members(nwid)?, vec![id]);
+        assert!(!client.get_member(nwid, id)?.authorized);
+        assert!(client.authorize_member(nwid, id)?.authorized);
+        assert!(!client.deauthorize_member(nwid, id)?.authorized);
+        client.delete_member(nwid, id)?;
+        assert!(client.list_members(nwid)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_checked() -> Result<(), Error> {
+        let mock = MockController::start("8056c2e21c", "sikrit")?;
+        let client = mock.client()?;
+        let mut net = client.new_network(&RootInterface::default())?;
+        net.name = Some("first".to_owned());
+        let mut stale = client.get_network(net.nwid.unwrap())?;
+        client.update_network_checked(&net)?;
+        stale.name = Some("second".to_owned());
+        let e = client.update_network_checked(&stale).unwrap_err();
+        assert_eq!(e.downcast_ref::<ZTError>().unwrap().code(), 302);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grant() -> Result<(), Error> {
+        let mock = MockController::start("8056c2e21c", "sikrit")?;
+        let client = mock.client()?;
+        let mut net = client.new_network(&RootInterface::default())?;
+        net.define_capability(Capability { id: 2000, rules: vec![] });
+        net.define_tag(TagDefinition { id: 1000, default: None });
+        let nwid = client.update_network_checked(&net)?.nwid.unwrap();
+        let id: NodeAddress = "deadbeef00".parse()?;
+        mock.join(nwid, id);
+
+        assert_eq!(client.grant_capability(nwid, id, 2000)?.capabilities, vec![2000]);
+        assert_eq!(client.set_member_tag(nwid, id, 1000, 7)?.tags, vec![(1000, 7)]);
+        let e = client.grant_capability(nwid, id, 2001).unwrap_err();
+        assert_eq!(e.downcast_ref::<ZTError>().unwrap().code(), 161);
+        let e = client.set_member_tag(nwid, id, 1001, 7).unwrap_err();
+        assert_eq!(e.downcast_ref::<ZTError>().unwrap().code(), 162);
+        assert!(client.revoke_capability(nwid, id, 2000)?.capabilities.is_empty());
+        Ok(())
+    }
+}
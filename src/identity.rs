@@ -0,0 +1,332 @@
+//! The identifiers zerotier hands around as hex strings, typed, so that a
+//! mistyped id is caught before it ever reaches the controller.
+//!
+//! A node address is 40 bits, written as 10 hex digits. A network id is 64
+//! bits, 16 hex digits: the address of its controller followed by a 24-bit
+//! number the controller picks. An identity is what a node keeps in
+//! `identity.public` (`address:0:public`) and `identity.secret`
+//! (`address:0:public:secret`), the keys being 64 bytes in hex.
+//!
+//! The IPv6 addresses zerotier makes up for a member follow from the ids
+//! alone, so they are known before the member ever joins. RFC4193 puts
+//! `fd`, the network id, `9993` and the node address together, all of the
+//! network sharing one /88. 6plane folds the network id into 32 bits behind
+//! `fc`, a /40 for the network, then gives each node the /80 after its
+//! address; the node itself is the first address of that.
+
+extern crate hex;
+
+use ipnet::Ipv6Net;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::ZTError;
+
+/// The 40-bit address of a zerotier node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeAddress(u64);
+
+impl NodeAddress {
+    /// Zerotier never hands out 0, nor addresses starting with `ff`, which
+    /// are reserved
+    pub fn new(address: u64) -> Result<Self, ZTError> {
+        if address == 0 || address > 0xff_ffff_ffff || address >> 32 == 0xff {
+            return Err(ZTError {
+                code: 141i32,
+                message: format!("invalid node address {:x}", address),
+            });
+        }
+        Ok(NodeAddress(address))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for NodeAddress {
+    type Err = ZTError;
+
+    fn from_str(s: &str) -> Result<Self, ZTError> {
+        match parse_hex(s, 10) {
+            Some(a) => NodeAddress::new(a),
+            None => Err(ZTError {
+                code: 141i32,
+                message: format!("invalid node address {}, want 10 hex digits", s),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for NodeAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:010x}", self.0)
+    }
+}
+
+/// A network id, the controller's address and a 24-bit suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NetworkId(u64);
+
+impl NetworkId {
+    /// Network `suffix` (at most `ffffff`) on `controller`
+    pub fn new(controller: NodeAddress, suffix: u32) -> Result<Self, ZTError> {
+        if suffix > 0xff_ffff {
+            return Err(ZTError {
+                code: 142i32,
+                message: format!("network suffix {:x} is over 24 bits", suffix),
+            });
+        }
+        Ok(NetworkId(controller.0 << 24 | u64::from(suffix)))
+    }
+
+    /// The node that controls this network
+    pub fn controller(self) -> NodeAddress {
+        NodeAddress(self.0 >> 24)
+    }
+
+    pub fn suffix(self) -> u32 {
+        (self.0 & 0xff_ffff) as u32
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// The /88 all RFC4193 addresses of the network are in
+    pub fn rfc4193_prefix(self) -> Ipv6Net {
+        let mut ip = [0u8; 16];
+        ip[0] = 0xfd;
+        ip[1..9].copy_from_slice(&self.0.to_be_bytes());
+        ip[9] = 0x99;
+        ip[10] = 0x93;
+        Ipv6Net::new(Ipv6Addr::from(ip), 88).unwrap()
+    }
+
+    /// The RFC4193 address of `node` on this network
+    pub fn rfc4193_address(self, node: NodeAddress) -> Ipv6Addr {
+        let mut ip = self.rfc4193_prefix().addr().octets();
+        ip[11..16].copy_from_slice(&node.0.to_be_bytes()[3..]);
+        Ipv6Addr::from(ip)
+    }
+
+    /// The /40 of the network's 6plane
+    pub fn sixplane_prefix(self) -> Ipv6Net {
+        let folded = (self.0 ^ (self.0 >> 32)) as u32;
+        let mut ip = [0u8; 16];
+        ip[0] = 0xfc;
+        ip[1..5].copy_from_slice(&folded.to_be_bytes());
+        Ipv6Net::new(Ipv6Addr::from(ip), 40).unwrap()
+    }
+
+    /// The /80 `node` gets on the 6plane, for itself and what it routes
+    pub fn sixplane_member_prefix(self, node: NodeAddress) -> Ipv6Net {
+        let mut ip = self.sixplane_prefix().addr().octets();
+        ip[5..10].copy_from_slice(&node.0.to_be_bytes()[3..]);
+        Ipv6Net::new(Ipv6Addr::from(ip), 80).unwrap()
+    }
+
+    /// The 6plane address of `node` itself
+    pub fn sixplane_address(self, node: NodeAddress) -> Ipv6Addr {
+        let mut ip = self.sixplane_member_prefix(node).addr().octets();
+        ip[15] = 1;
+        Ipv6Addr::from(ip)
+    }
+}
+
+impl FromStr for NetworkId {
+    type Err = ZTError;
+
+    fn from_str(s: &str) -> Result<Self, ZTError> {
+        let invalid = || ZTError {
+            code: 142i32,
+            message: format!("invalid network id {}, want 16 hex digits", s),
+        };
+        let id = parse_hex(s, 16).ok_or_else(invalid)?;
+        // the controller part has to be a valid node address too
+        NodeAddress::new(id >> 24).map_err(|_| invalid())?;
+        Ok(NetworkId(id))
+    }
+}
+
+impl fmt::Display for NetworkId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Exactly `digits` hex digits, nothing else (no sign, no 0x)
+fn parse_hex(s: &str, digits: usize) -> Option<u64> {
+    if s.len() != digits || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(s, 16).ok()
+}
+
+/// Both go over the wire as their hex string
+macro_rules! hex_serde {
+    ($t:ident) => {
+        impl serde::Serialize for $t {
+            fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                s.collect_str(self)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $t {
+            fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(d)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+hex_serde!(NodeAddress);
+hex_serde!(NetworkId);
+
+/// A node's identity, with its secret key when read from `identity.secret`
+#[derive(Clone)]
+pub struct Identity {
+    address: NodeAddress,
+    public: [u8; 64],
+    secret: Option<[u8; 64]>,
+}
+
+impl Identity {
+    /// Read `identity.public` or `identity.secret`
+    pub fn load(path: &Path) -> Result<Self, failure::Error> {
+        let s = std::fs::read_to_string(path)?;
+        Ok(s.parse()?)
+    }
+
+    pub fn address(&self) -> NodeAddress {
+        self.address
+    }
+
+    /// The curve25519 key in the first half, the ed25519 key in the second
+    pub fn public_key(&self) -> &[u8; 64] {
+        &self.public
+    }
+
+    pub fn secret_key(&self) -> Option<&[u8; 64]> {
+        self.secret.as_ref()
+    }
+
+    /// What goes into `identity.secret`, `None` for a public identity
+    pub fn to_secret_string(&self) -> Option<String> {
+        self.secret
+            .map(|s| format!("{}:{}", self, hex::encode(&s[..])))
+    }
+}
+
+impl FromStr for Identity {
+    type Err = ZTError;
+
+    fn from_str(s: &str) -> Result<Self, ZTError> {
+        let s = s.trim();
+        let invalid = || ZTError {
+            code: 143i32,
+            message: format!("invalid identity {}", s.split(':').take(3).collect::<Vec<_>>().join(":")),
+        };
+        let fields: Vec<&str> = s.split(':').collect();
+        if fields.len() < 3 || fields.len() > 4 || fields[1] != "0" {
+            return Err(invalid());
+        }
+        let address = fields[0].parse().map_err(|_| invalid())?;
+        let public = decode_key(fields[2]).ok_or_else(invalid)?;
+        let secret = match fields.get(3) {
+            Some(k) => Some(decode_key(k).ok_or_else(invalid)?),
+            None => None,
+        };
+        Ok(Identity {
+            address,
+            public,
+            secret,
+        })
+    }
+}
+
+/// The public form, `address:0:public`; the secret never shows up here
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:0:{}", self.address, hex::encode(&self.public[..]))
+    }
+}
+
+/// Leave the secret out of debug output as well
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Identity({}, secret: {})", self, self.secret.is_some())
+    }
+}
+
+fn decode_key(h: &str) -> Option<[u8; 64]> {
+    let mut key = [0u8; 64];
+    match hex::decode(h) {
+        Ok(ref k) if k.len() == 64 => key.copy_from_slice(k),
+        _ => return None,
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PUBLIC: &str = "3b2caa9d4e6bbe4d0f36dc1d1c4a7c9f6e0c7dd2bae2e1d7e26b4f6e9a1d7b8c\
+                          62f3a8f6d93c9bde1e5f9b4c7a2d8e3f1a6b9c0d7e2f4a5b8c1d3e6f9a0b2c4d";
+
+    #[test]
+    fn test_ids() -> Result<(), ZTError> {
+        let nwid: NetworkId = "8056c2e21c000001".parse()?;
+        assert_eq!(nwid.controller(), "8056c2e21c".parse()?);
+        assert_eq!(nwid.suffix(), 1);
+        assert_eq!(NetworkId::new(nwid.controller(), 1)?, nwid);
+        assert_eq!(nwid.to_string(), "8056c2e21c000001");
+        assert_eq!(serde_json::to_string(&nwid).unwrap(), "\"8056c2e21c000001\"");
+
+        for bad in &["8056c2e21c00001", "8056c2e21c0000011", "+056c2e21c000001", "0000000000000001"] {
+            assert_eq!(bad.parse::<NetworkId>().unwrap_err().code(), 142);
+        }
+        for bad in &["8056c2e21", "ff56c2e21c", "0000000000", "8056c2e21g"] {
+            assert_eq!(bad.parse::<NodeAddress>().unwrap_err().code(), 141);
+        }
+        assert!(serde_json::from_str::<NodeAddress>("\"8056c2e21\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ipv6_addresses() -> Result<(), ZTError> {
+        let nwid: NetworkId = "8056c2e21c000001".parse()?;
+        let node: NodeAddress = "deadbeef00".parse()?;
+        assert_eq!(nwid.rfc4193_prefix().to_string(), "fd80:56c2:e21c:0:199:9300::/88");
+        assert_eq!(nwid.rfc4193_address(node).to_string(), "fd80:56c2:e21c:0:199:93de:adbe:ef00");
+        // 8056c2e2 ^ 1c000001 = 9c56c2e3
+        assert_eq!(nwid.sixplane_prefix().to_string(), "fc9c:56c2:e300::/40");
+        assert_eq!(nwid.sixplane_member_prefix(node).to_string(), "fc9c:56c2:e3de:adbe:ef00::/80");
+        assert_eq!(nwid.sixplane_address(node).to_string(), "fc9c:56c2:e3de:adbe:ef00::1");
+        assert!(nwid.sixplane_prefix().contains(&nwid.sixplane_address(node)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_identity() -> Result<(), ZTError> {
+        let public = format!("8056c2e21c:0:{}", PUBLIC);
+        let id: Identity = format!("{}\n", public).parse()?;
+        assert_eq!(id.address().to_string(), "8056c2e21c");
+        assert_eq!(id.to_string(), public);
+        assert!(id.secret_key().is_none());
+
+        let secret = format!("{}:{}", public, "a5".repeat(64));
+        let id: Identity = secret.parse()?;
+        assert_eq!(id.to_string(), public);
+        assert_eq!(id.to_secret_string(), Some(secret));
+        assert!(!format!("{:?}", id).contains("a5a5"));
+
+        assert_eq!("8056c2e21c:1:00".parse::<Identity>().unwrap_err().code(), 143);
+        assert_eq!(format!("8056c2e21c:0:{}", &PUBLIC[2..]).parse::<Identity>().unwrap_err().code(), 143);
+        Ok(())
+    }
+}
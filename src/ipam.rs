@@ -0,0 +1,290 @@
+//! Which addresses of a network's pools are taken, and by whom. Built from
+//! the network and its members' `ipAssignments`, so it can pick the next
+//! free address for a member, pin a given one, and point out addresses
+//! that are handed out twice or lie outside every pool.
+//!
+//! Like the controller, the next free IPv4 address never ends in .0 or
+//! .255; asking for one of those explicitly is fine.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::{Member, NodeAddress, RootInterface, ZTError};
+
+/// How full one pool is
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolUsage {
+    /// Index in `RootInterface.ip_assignment_pools`
+    pub pool: usize,
+    pub start: IpAddr,
+    pub end: IpAddr,
+    pub size: u128,
+    pub used: u128,
+}
+
+impl fmt::Display for PoolUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pool {}: {}-{} {}/{} used ({:.1}%)",
+            self.pool,
+            self.start,
+            self.end,
+            self.used,
+            self.size,
+            self.used as f64 * 100.0 / self.size as f64
+        )
+    }
+}
+
+/// What's wrong with the assignments of a network
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpamProblem {
+    /// More than one member has the address
+    Duplicate { ip: IpAddr, members: Vec<NodeAddress> },
+    /// The address is in none of the pools
+    OutsidePools { ip: IpAddr, member: NodeAddress },
+}
+
+impl IpamProblem {
+    pub fn code(&self) -> i32 {
+        match self {
+            IpamProblem::Duplicate { .. } => 185,
+            IpamProblem::OutsidePools { .. } => 186,
+        }
+    }
+}
+
+impl fmt::Display for IpamProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpamProblem::Duplicate { ip, members } => {
+                let members: Vec<String> = members.iter().map(|m| m.to_string()).collect();
+                write!(f, "{} is assigned to {}", ip, members.join(", "))
+            }
+            IpamProblem::OutsidePools { ip, member } => {
+                write!(f, "{} of {} is outside of the pools", ip, member)
+            }
+        }
+    }
+}
+
+/// The pools of a network and who has which address
+#[derive(Debug, Clone, Default)]
+pub struct Ipam {
+    pools: Vec<(IpAddr, IpAddr)>,
+    used: BTreeMap<IpAddr, Vec<NodeAddress>>,
+}
+
+impl Ipam {
+    /// Members without an id are skipped, we couldn't tell who they are
+    pub fn new(net: &RootInterface, members: &[Member]) -> Self {
+        let mut ipam = Ipam {
+            pools: net
+                .ip_assignment_pools
+                .iter()
+                .map(|p| (p.ip_range_start, p.ip_range_end))
+                .collect(),
+            used: BTreeMap::new(),
+        };
+        for m in members {
+            if let Some(id) = m.id {
+                for ip in &m.ip_assignments {
+                    ipam.used.entry(*ip).or_default().push(id);
+                }
+            }
+        }
+        ipam
+    }
+
+    /// The addresses in use, with the members that have them
+    pub fn used(&self) -> &BTreeMap<IpAddr, Vec<NodeAddress>> {
+        &self.used
+    }
+
+    /// The index of the pool `ip` is in
+    pub fn pool_of(&self, ip: IpAddr) -> Option<usize> {
+        self.pools.iter().position(|&(s, e)| in_range(ip, s, e))
+    }
+
+    /// In a pool, and nobody has it
+    pub fn is_free(&self, ip: IpAddr) -> bool {
+        self.pool_of(ip).is_some() && !self.used.contains_key(&ip)
+    }
+
+    /// The free addresses of a pool, in order
+    pub fn free(&self, pool: usize) -> impl Iterator<Item = IpAddr> + '_ {
+        let (start, end) = self.pools[pool];
+        (to_u128(start)..=to_u128(end))
+            .map(move |n| from_u128(n, start.is_ipv4()))
+            .filter(move |ip| allocatable(*ip) && !self.used.contains_key(ip))
+    }
+
+    /// Give `member` the first free address of the first pool of the
+    /// family that has one
+    pub fn allocate_next(&mut self, member: NodeAddress, ipv6: bool) -> Result<IpAddr, ZTError> {
+        let ip = (0..self.pools.len())
+            .filter(|&p| self.pools[p].0.is_ipv6() == ipv6)
+            .find_map(|p| self.free(p).next());
+        match ip {
+            Some(ip) => {
+                self.used.insert(ip, vec![member]);
+                Ok(ip)
+            }
+            None => Err(ZTError {
+                code: 181i32,
+                message: format!("no free IPv{} address left in the pools", if ipv6 { 6 } else { 4 }),
+            }),
+        }
+    }
+
+    /// Give `member` exactly `ip`, which has to be a free pool address
+    pub fn allocate(&mut self, member: NodeAddress, ip: IpAddr) -> Result<(), ZTError> {
+        if self.pool_of(ip).is_none() {
+            return Err(ZTError {
+                code: 182i32,
+                message: format!("{} is in none of the pools", ip),
+            });
+        }
+        if let Some(owners) = self.used.get(&ip) {
+            let owner = owners.first().map(|o| o.to_string()).unwrap_or_default();
+            return Err(ZTError {
+                code: 183i32,
+                message: format!("{} is already assigned to {}", ip, owner),
+            });
+        }
+        self.used.insert(ip, vec![member]);
+        Ok(())
+    }
+
+    /// Take `ip` away from `member`
+    pub fn release(&mut self, member: NodeAddress, ip: IpAddr) -> Result<(), ZTError> {
+        let owners = self.used.get_mut(&ip);
+        match owners {
+            Some(o) if o.contains(&member) => {
+                o.retain(|m| *m != member);
+                if o.is_empty() {
+                    self.used.remove(&ip);
+                }
+                Ok(())
+            }
+            _ => Err(ZTError {
+                code: 184i32,
+                message: format!("{} is not assigned to {}", ip, member),
+            }),
+        }
+    }
+
+    /// Addresses handed out twice, then those outside the pools
+    pub fn problems(&self) -> Vec<IpamProblem> {
+        let mut problems: Vec<IpamProblem> = self
+            .used
+            .iter()
+            .filter(|(_, m)| m.len() > 1)
+            .map(|(ip, m)| IpamProblem::Duplicate {
+                ip: *ip,
+                members: m.clone(),
+            })
+            .collect();
+        for (ip, members) in &self.used {
+            if self.pool_of(*ip).is_none() {
+                for m in members {
+                    problems.push(IpamProblem::OutsidePools { ip: *ip, member: *m });
+                }
+            }
+        }
+        problems
+    }
+
+    /// Size and use of every pool, in the order of the network
+    pub fn utilization(&self) -> Vec<PoolUsage> {
+        self.pools
+            .iter()
+            .enumerate()
+            .map(|(pool, &(start, end))| PoolUsage {
+                pool,
+                start,
+                end,
+                size: (to_u128(end) + 1).saturating_sub(to_u128(start)),
+                used: self.used.keys().filter(|ip| in_range(**ip, start, end)).count() as u128,
+            })
+            .collect()
+    }
+}
+
+fn in_range(ip: IpAddr, start: IpAddr, end: IpAddr) -> bool {
+    ip.is_ipv4() == start.is_ipv4() && to_u128(ip) >= to_u128(start) && to_u128(ip) <= to_u128(end)
+}
+
+fn allocatable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => !matches!(v4.octets()[3], 0 | 255),
+        IpAddr::V6(_) => true,
+    }
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(u32::from(v4)),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+fn from_u128(n: u128, v4: bool) -> IpAddr {
+    if v4 {
+        IpAddr::V4(Ipv4Addr::from(n as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(n))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn member(id: &str, ips: &[&str]) -> Member {
+        Member {
+            id: Some(id.parse().unwrap()),
+            ip_assignments: ips.iter().map(|i| i.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ipam() -> Result<(), ZTError> {
+        let mut net = RootInterface::default();
+        net.ip_assignment_pools[0].set_range("10.1.0.254".parse().unwrap(), "10.1.1.3".parse().unwrap());
+        net.add_subnet("fd00::1".parse().unwrap(), "fd00::ff".parse().unwrap(), 64)?;
+        let members = vec![
+            member("deadbeef00", &["10.1.1.1", "10.9.0.1"]),
+            member("deadbeef01", &["10.1.1.1"]),
+        ];
+        let mut ipam = Ipam::new(&net, &members);
+        let (a, b) = ("deadbeef00".parse()?, "deadbeef01".parse()?);
+
+        assert_eq!(
+            ipam.problems().iter().map(|p| p.code()).collect::<Vec<_>>(),
+            vec![185, 186]
+        );
+        // .255 and .0 are skipped, .1 is taken
+        assert_eq!(ipam.allocate_next(a, false)?, "10.1.0.254".parse::<IpAddr>().unwrap());
+        assert_eq!(ipam.allocate_next(a, false)?, "10.1.1.2".parse::<IpAddr>().unwrap());
+        assert_eq!(ipam.allocate_next(a, true)?, "fd00::1".parse::<IpAddr>().unwrap());
+        ipam.allocate(b, "10.1.1.3".parse().unwrap())?;
+        assert_eq!(ipam.allocate_next(b, false).unwrap_err().code(), 181);
+        assert_eq!(ipam.allocate(b, "10.2.0.1".parse().unwrap()).unwrap_err().code(), 182);
+        assert_eq!(ipam.allocate(b, "10.1.1.2".parse().unwrap()).unwrap_err().code(), 183);
+
+        let usage = ipam.utilization();
+        assert_eq!((usage[0].size, usage[0].used), (6, 4));
+        assert_eq!((usage[1].size, usage[1].used), (255, 1));
+
+        assert_eq!(ipam.release(b, "10.1.1.2".parse().unwrap()).unwrap_err().code(), 184);
+        ipam.release(b, "10.1.1.3".parse().unwrap())?;
+        assert!(ipam.is_free("10.1.1.3".parse().unwrap()));
+        ipam.release(b, "10.1.1.1".parse().unwrap())?;
+        assert_eq!(ipam.problems().len(), 1);
+        Ok(())
+    }
+}
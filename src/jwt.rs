@@ -0,0 +1,155 @@
+//! Bearer tokens for the api. A token is a JWT signed with HS256 (shared
+//! secret), RS256 or ES256 (we only hold the public key), and carries the
+//! scopes its holder may use:
+//!
+//! ```text
+//!     network:create                   create networks
+//!     network:<nwid>:read              read a network
+//!     network:<nwid>:write             change or destroy a network
+//!     network:<nwid>:members:read      list and read members
+//!     network:<nwid>:members:write     (de)authorize and delete members
+//!     moon:write                       deploy the moon
+//! ```
+//!
+//! A scope also grants everything below it, `network:<nwid>` is all of a
+//! tenant's network, and a `*` stands in for any one part, so `network:*`
+//! is an admin. Ids are hex, so their case doesn't matter.
+
+extern crate jsonwebtoken;
+
+use failure::Error;
+
+use self::jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+
+use super::ZTError;
+
+/// What we look at in a token, `exp` is checked by the validation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Option<String>,
+    pub exp: u64,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl Claims {
+    /// Does any of our scopes grant `wanted`?
+    pub fn allows(&self, wanted: &str) -> bool {
+        self.scopes.iter().any(|s| scope_grants(s, wanted))
+    }
+
+    /// Like `allows`, as an error for the api to hand back
+    pub fn require(&self, wanted: &str) -> Result<(), ZTError> {
+        if !self.allows(wanted) {
+            return Err(ZTError {
+                code: 313i32,
+                message: format!("token lacks scope {}", wanted),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn scope_grants(granted: &str, wanted: &str) -> bool {
+    let granted: Vec<&str> = granted.split(':').collect();
+    let wanted: Vec<&str> = wanted.split(':').collect();
+    granted.len() <= wanted.len()
+        && granted
+            .iter()
+            .zip(wanted.iter())
+            .all(|(g, w)| *g == "*" || g.eq_ignore_ascii_case(w))
+}
+
+/// Verifies the tokens of api requests
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtAuth {
+    /// Tokens signed with a shared secret
+    pub fn hs256(secret: &[u8]) -> Self {
+        JwtAuth {
+            key: DecodingKey::from_secret(secret),
+            validation: Validation::new(Algorithm::HS256),
+        }
+    }
+
+    /// Tokens signed with an RSA key, we get the PEM public key
+    pub fn rs256(pem: &[u8]) -> Result<Self, Error> {
+        Ok(JwtAuth {
+            key: DecodingKey::from_rsa_pem(pem)?,
+            validation: Validation::new(Algorithm::RS256),
+        })
+    }
+
+    /// Tokens signed with a P-256 key, we get the PEM public key
+    pub fn es256(pem: &[u8]) -> Result<Self, Error> {
+        Ok(JwtAuth {
+            key: DecodingKey::from_ec_pem(pem)?,
+            validation: Validation::new(Algorithm::ES256),
+        })
+    }
+
+    /// Check the `Authorization` header of a request and hand back the
+    /// claims of its bearer token
+    pub fn verify(&self, authorization: Option<&str>) -> Result<Claims, ZTError> {
+        let token = match authorization {
+            Some(a) if a.starts_with("Bearer ") => a[7..].trim(),
+            _ => {
+                return Err(ZTError {
+                    code: 312i32,
+                    message: "bearer token required".to_string(),
+                })
+            }
+        };
+        match decode::<Claims>(token, &self.key, &self.validation) {
+            Ok(data) => Ok(data.claims),
+            Err(e) => Err(ZTError {
+                code: 312i32,
+                message: format!("invalid token: {}", e),
+            }),
+        }
+    }
+
+    /// Verify the token and check it grants `scope`
+    pub fn authorize(&self, authorization: Option<&str>, scope: &str) -> Result<Claims, ZTError> {
+        let claims = self.verify(authorization)?;
+        claims.require(scope)?;
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::jsonwebtoken::{encode, EncodingKey, Header};
+    use super::*;
+
+    /// An HS256 token with `scopes`, valid for an hour
+    pub fn token(secret: &[u8], scopes: &[&str]) -> String {
+        let claims = Claims {
+            sub: Some("tenant".to_owned()),
+            exp: jsonwebtoken::get_current_timestamp() + 3600,
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        };
+        let t = encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap();
+        format!("Bearer {}", t)
+    }
+
+    #[test]
+    fn test_scopes() {
+        let jwt = JwtAuth::hs256(b"sikrit");
+        let t = token(b"sikrit", &["network:8056c2e21c000001", "network:*:read"]);
+        assert!(jwt.authorize(Some(&t), "network:8056c2e21c000001:members:write").is_ok());
+        assert!(jwt.authorize(Some(&t), "network:8056c2e21c000002:read").is_ok());
+        let e = jwt.authorize(Some(&t), "network:8056c2e21c000002:write").unwrap_err();
+        assert_eq!(e.code(), 313);
+        assert_eq!(jwt.authorize(Some(&t), "network").unwrap_err().code(), 313);
+        let upper = token(b"sikrit", &["network:8056C2E21C000003"]);
+        assert!(jwt.authorize(Some(&upper), "network:8056c2e21c000003:read").is_ok());
+
+        assert_eq!(jwt.verify(None).unwrap_err().code(), 312);
+        let forged = token(b"guess", &["network:*"]);
+        assert_eq!(jwt.verify(Some(&forged)).unwrap_err().code(), 312);
+    }
+}
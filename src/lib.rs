@@ -24,13 +24,20 @@
 //!
 //!
 
-// TODO: 
-//   - is_sibling
-//   - vec!(default_rules)
-
-
+// failure_derive predates the non_local_definitions and check-cfg lints,
+// and its expansion trips both.
+#![allow(non_local_definitions, unexpected_cfgs)]
 
 pub mod commands;
+pub mod identity;
+pub mod ipam;
+pub mod jwt;
+pub mod moon;
+pub mod policy;
+pub mod rules;
+pub mod simulate;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod server;
 
 extern crate failure;
@@ -47,9 +54,9 @@ extern crate ipnet;
 
 use ipnet::PrefixLenError;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::IpAddr;
 
-use failure::Error;
+pub use identity::{Identity, NetworkId, NodeAddress};
 
 const ZT_ETHERTYPE_IPV4: u16 = 0x0800;
 const ZT_ETHERTYPE_ARP: u16 = 0x0806;
@@ -72,6 +79,20 @@ pub struct ZTError {
     message: String,
 }
 
+impl ZTError {
+    pub fn new(code: i32, message: &str) -> Self {
+        ZTError {
+            code,
+            message: message.to_owned(),
+        }
+    }
+
+    /// The code, so a binary can hand it back as its exit status
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+}
+
 /// Range of addresses to allocate from IPv4/6
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IpAssignmentPools {
@@ -107,20 +128,160 @@ pub struct RootInterface {
     pub auth: Option<String>,
     pub name: Option<String>,
     pub private: bool,
-    pub id: Option<String>,
-    pub nwid: Option<String>,
-    #[serde(rename = "allowPassiveBridging")]
-    allow_passive_bridging: bool,
-    #[serde(rename = "v4AssignMode")]
-    v4_assign_mode: String,
-    #[serde(rename = "v6AssignMode")]
-    v6_assign_mode: String,
+    pub id: Option<NetworkId>,
+    pub nwid: Option<NetworkId>,
+    /// Bumped by the controller on every change, we only read it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
+    #[serde(rename = "allowPassiveBridging", default)]
+    pub allow_passive_bridging: bool,
+    #[serde(rename = "enableBroadcast", default = "default_enable_broadcast")]
+    pub enable_broadcast: bool,
+    /// How many members a multicast goes to at most
+    #[serde(rename = "multicastLimit", default = "default_multicast_limit")]
+    pub multicast_limit: u32,
+    #[serde(default = "default_mtu")]
+    pub mtu: u32,
+    #[serde(default)]
+    pub dns: Dns,
+    #[serde(rename = "v4AssignMode", default = "default_v4_assign_mode")]
+    pub v4_assign_mode: V4AssignMode,
+    #[serde(rename = "v6AssignMode", default)]
+    pub v6_assign_mode: V6AssignMode,
     pub routes: Vec<Routes>,
     #[serde(rename = "ipAssignmentPools")]
     pub ip_assignment_pools: Vec<IpAssignmentPools>,
     pub rules: Vec<Rules>,
-    pub capabilities: Option<Vec<Rules>>,
-    pub tags: Option<Vec<Rules>>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    #[serde(default)]
+    pub tags: Vec<TagDefinition>,
+}
+
+/// The search domain and name servers pushed to members, empty for none
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Dns {
+    #[serde(default)]
+    pub domain: String,
+    #[serde(default)]
+    pub servers: Vec<IpAddr>,
+}
+
+/// The controller's defaults, for networks that don't say
+fn default_enable_broadcast() -> bool {
+    true
+}
+
+/// Addresses from the pools, or what would the pools be for
+fn default_v4_assign_mode() -> V4AssignMode {
+    V4AssignMode { zt: true }
+}
+
+fn default_multicast_limit() -> u32 {
+    32
+}
+
+fn default_mtu() -> u32 {
+    2800
+}
+
+/// The MTUs the controller keeps as they are, it clamps the others
+pub const MTU_RANGE: std::ops::RangeInclusive<u32> = 1280..=10000;
+
+/// How members get IPv4 addresses: `zt` draws them from the pools
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct V4AssignMode {
+    pub zt: bool,
+}
+
+impl Default for V4AssignMode {
+    fn default() -> Self {
+        default_v4_assign_mode()
+    }
+}
+
+/// How members get IPv6 addresses: `zt` draws them from the pools,
+/// `rfc4193` and `6plane` make them from the network id and the member's
+/// address, see `NetworkId::rfc4193_address` and `NetworkId::sixplane_address`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct V6AssignMode {
+    pub zt: bool,
+    #[serde(rename = "6plane")]
+    pub sixplane: bool,
+    pub rfc4193: bool,
+}
+
+/// The controller reports assign modes as an object of flags
+/// (`{"zt": true, "6plane": false}`), older versions of us posted a comma
+/// separated string (`"zt,6plane"`, `"none"`); both read the same.
+fn assign_flags<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    let v = <serde_json::Value as serde::Deserialize>::deserialize(d)?;
+    Ok(match v {
+        serde_json::Value::String(s) => s.split(',').map(|m| m.trim().to_owned()).collect(),
+        serde_json::Value::Object(m) => m
+            .into_iter()
+            .filter(|(_, on)| on.as_bool().unwrap_or(false))
+            .map(|(k, _)| k)
+            .collect(),
+        _ => Vec::new(),
+    })
+}
+
+/// Parse modes as the CLI takes them, comma separated or `none`
+fn parse_flags<'a>(s: &'a str, known: &[&str]) -> Result<Vec<&'a str>, ZTError> {
+    let flags: Vec<&str> = s.split(',').map(|m| m.trim()).filter(|m| *m != "none").collect();
+    match flags.iter().find(|f| !known.contains(f)) {
+        Some(f) => Err(ZTError {
+            code: 119i32,
+            message: format!("unknown assign mode {}, want {} or none", f, known.join(",")),
+        }),
+        None => Ok(flags),
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for V4AssignMode {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let flags = assign_flags(d)?;
+        Ok(V4AssignMode {
+            zt: flags.iter().any(|f| f == "zt"),
+        })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for V6AssignMode {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let flags = assign_flags(d)?;
+        let on = |f: &str| flags.iter().any(|m| m == f);
+        Ok(V6AssignMode {
+            zt: on("zt"),
+            sixplane: on("6plane"),
+            rfc4193: on("rfc4193"),
+        })
+    }
+}
+
+impl std::str::FromStr for V4AssignMode {
+    type Err = ZTError;
+
+    fn from_str(s: &str) -> Result<Self, ZTError> {
+        let flags = parse_flags(s, &["zt"])?;
+        Ok(V4AssignMode {
+            zt: flags.contains(&"zt"),
+        })
+    }
+}
+
+impl std::str::FromStr for V6AssignMode {
+    type Err = ZTError;
+
+    fn from_str(s: &str) -> Result<Self, ZTError> {
+        let flags = parse_flags(s, &["zt", "6plane", "rfc4193"])?;
+        Ok(V6AssignMode {
+            zt: flags.contains(&"zt"),
+            sixplane: flags.contains(&"6plane"),
+            rfc4193: flags.contains(&"rfc4193"),
+        })
+    }
 }
 
 /// we would not need a default per se, but it can come in handy for the API
@@ -132,14 +293,20 @@ impl Default for RootInterface {
             private: true,
             id: None,
             nwid: None,
+            revision: None,
             allow_passive_bridging: false,
-            v4_assign_mode: "zt".to_owned(),
-            v6_assign_mode: "none".to_owned(),
+            enable_broadcast: default_enable_broadcast(),
+            multicast_limit: default_multicast_limit(),
+            mtu: default_mtu(),
+            dns: Dns::default(),
+            v4_assign_mode: default_v4_assign_mode(),
+            v6_assign_mode: V6AssignMode::default(),
             routes: vec![Routes::default()],
             ip_assignment_pools: vec![IpAssignmentPools::default()],
-            rules: vec![Rules::default()],
-            capabilities: None,
-            tags: None,
+            // let everything through until someone sets rules
+            rules: vec![Rules::new(Rule::ActionAccept)],
+            capabilities: Vec::new(),
+            tags: Vec::new(),
         }
     }
 }
@@ -149,27 +316,133 @@ impl RootInterface {
     /// need to use an IP __in__ the network to route to. This function verifies
     /// the validity of the routes in the request, before sending it to the
     /// zerotier-controller microservice.
-    pub fn verify_routes(&self) -> Result<(), ZTError> {
-        let nets = self.routes.clone();
-        for r in &self.routes {
-            if r.via.is_some() {
-                // do we have a net that can contain that gw ?
-                for n in &nets {
-                    // we can unwrap here, as it's typechecked before
-                    if n.target.contains(&r.via.unwrap()) {
-                        return Ok(());
+    ///
+    /// Every route is checked, and all problems come back in the report.
+    pub fn verify_routes(&self) -> Result<(), RouteReport> {
+        let report = self.check_routes();
+        if report.is_ok() {
+            Ok(())
+        } else {
+            Err(report)
+        }
+    }
+
+    /// Walk the route table and the pools, and note every problem with
+    /// the index of the route (or pool) it's about.
+    pub fn check_routes(&self) -> RouteReport {
+        let mut report = RouteReport::default();
+        for (i, r) in self.routes.iter().enumerate() {
+            if let Some(first) = self.routes[..i].iter().position(|f| f.target == r.target) {
+                report.problems.push(RouteProblem::DuplicateTarget { route: i, first });
+            }
+            let gw = match r.via {
+                Some(gw) => gw,
+                None => continue,
+            };
+            if gw.is_ipv4() != r.target.addr().is_ipv4() {
+                report.problems.push(RouteProblem::MixedFamilies { route: i, via: gw });
+                continue;
+            }
+            if r.target.contains(&gw) {
+                report.problems.push(RouteProblem::GatewayInOwnTarget { route: i, via: gw });
+            }
+            // do we have a net that can contain that gw ?
+            let carried = self
+                .routes
+                .iter()
+                .any(|n| n.via.is_none() && n.target.contains(&gw));
+            if !carried {
+                report.problems.push(RouteProblem::NoCarryingNet { route: i, via: gw });
+            }
+        }
+        for (i, p) in self.ip_assignment_pools.iter().enumerate() {
+            let carried = self.routes.iter().any(|n| {
+                n.via.is_none()
+                    && n.target.contains(&p.ip_range_start)
+                    && n.target.contains(&p.ip_range_end)
+            });
+            if !carried {
+                report.problems.push(RouteProblem::UncarriedPool { pool: i });
+            }
+        }
+        report
+    }
+
+    /// The problems of `check_routes`, each with the route (or pool) it is
+    /// about rather than its index, which moves when a route comes or goes
+    fn keyed_route_problems(&self) -> Vec<((i32, String), RouteProblem)> {
+        self.check_routes()
+            .problems
+            .into_iter()
+            .map(|p| {
+                let about = match p {
+                    RouteProblem::NoCarryingNet { route, .. }
+                    | RouteProblem::GatewayInOwnTarget { route, .. }
+                    | RouteProblem::DuplicateTarget { route, .. }
+                    | RouteProblem::MixedFamilies { route, .. } => {
+                        format!("{} via {:?}", self.routes[route].target, self.routes[route].via)
                     }
+                    RouteProblem::UncarriedPool { pool } => {
+                        let p = &self.ip_assignment_pools[pool];
+                        format!("{}-{}", p.ip_range_start, p.ip_range_end)
+                    }
+                };
+                ((p.code(), about), p)
+            })
+            .collect()
+    }
+
+    /// What `check_routes` finds now that it didn't find `before` a change.
+    /// Problems a live network already had don't block unrelated changes.
+    fn introduced_route_problems(&self, before: Vec<((i32, String), RouteProblem)>) -> RouteReport {
+        let mut before: Vec<(i32, String)> = before.into_iter().map(|(k, _)| k).collect();
+        let mut report = RouteReport::default();
+        for (key, p) in self.keyed_route_problems() {
+            match before.iter().position(|k| *k == key) {
+                Some(i) => {
+                    before.swap_remove(i);
                 }
+                None => report.problems.push(p),
             }
-            return Err(ZTError {
-                code: 101i32,
-                message: "no carrying net for gw".to_string(),
-            });
         }
-        return Err(ZTError {
-            code: 102i32,
-            message: "shouldn't get here (fn verify_routes)".to_string(),
-        });
+        report
+    }
+
+    /// Add a route to `target` via `gw` and check that the network can
+    /// still reach all its gateways. On error the routes are left untouched.
+    pub fn add_route(&mut self, target: IpNet, gw: Option<IpAddr>) -> Result<(), ZTError> {
+        let before = self.keyed_route_problems();
+        let mut route = Routes::default();
+        route.with(target, gw);
+        self.routes.push(route);
+        let report = self.introduced_route_problems(before);
+        if !report.is_ok() {
+            self.routes.pop();
+            return Err(report.into());
+        }
+        Ok(())
+    }
+
+    /// Remove the route to `target`. Removing a carrying net that a gateway
+    /// still lives in is refused, just like adding an unreachable gateway.
+    pub fn del_route(&mut self, target: IpNet) -> Result<Routes, ZTError> {
+        let idx = match self.routes.iter().position(|r| r.target == target) {
+            Some(i) => i,
+            None => {
+                return Err(ZTError {
+                    code: 108i32,
+                    message: format!("no route to {}", target),
+                })
+            }
+        };
+        let before = self.keyed_route_problems();
+        let removed = self.routes.remove(idx);
+        let report = self.introduced_route_problems(before);
+        if !report.is_ok() {
+            self.routes.insert(idx, removed);
+            return Err(report.into());
+        }
+        Ok(removed)
     }
 
     /// There is no into() for an IpAddr/mask, so we add it here.
@@ -181,24 +454,147 @@ impl RootInterface {
         })
     }
 
-    /// Are the 2 ipaddrs valid for that range?
-    fn validate_sibling() {}
+    /// Append a pool from `s` to `e` and the route that carries it to an
+    /// existing network. The pool has to pass `validate_pool`, one that
+    /// overlaps a pool we already have is refused, and an IPv6 pool switches
+    /// on zt assignment for IPv6.
+    pub fn add_subnet(&mut self, s: IpAddr, e: IpAddr, m: u8) -> Result<(), ZTError> {
+        let target = RootInterface::validate_pool(s, e, m)?;
+        for p in &self.ip_assignment_pools {
+            let same_family = p.ip_range_start.is_ipv4() == s.is_ipv4();
+            if same_family && s <= p.ip_range_end && p.ip_range_start <= e {
+                return Err(ZTError {
+                    code: 106i32,
+                    message: format!(
+                        "range {}-{} overlaps pool {}-{}",
+                        s, e, p.ip_range_start, p.ip_range_end
+                    ),
+                });
+            }
+        }
+        let mut pool = IpAssignmentPools::default();
+        pool.set_range(s, e);
+        self.ip_assignment_pools.push(pool);
+        if !self.routes.iter().any(|r| r.target == target) {
+            self.routes.push(Routes {
+                target,
+                ..Default::default()
+            });
+        }
+        if s.is_ipv6() {
+            self.v6_assign_mode.zt = true;
+        }
+        Ok(())
+    }
+
+    /// Add a capability, or replace the one with the same id
+    pub fn define_capability(&mut self, cap: Capability) {
+        match self.capabilities.iter_mut().find(|c| c.id == cap.id) {
+            Some(c) => *c = cap,
+            None => self.capabilities.push(cap),
+        }
+    }
+
+    /// Add a tag, or replace the one with the same id
+    pub fn define_tag(&mut self, tag: TagDefinition) {
+        match self.tags.iter_mut().find(|t| t.id == tag.id) {
+            Some(t) => *t = tag,
+            None => self.tags.push(tag),
+        }
+    }
+
+    pub fn capability(&self, id: u32) -> Option<&Capability> {
+        self.capabilities.iter().find(|c| c.id == id)
+    }
+
+    pub fn tag(&self, id: u32) -> Option<&TagDefinition> {
+        self.tags.iter().find(|t| t.id == id)
+    }
+
+    /// The controller clamps the MTU into `MTU_RANGE`; we'd rather refuse
+    /// one outside than have it changed behind the user's back
+    pub fn set_mtu(&mut self, mtu: u32) -> Result<(), ZTError> {
+        if !MTU_RANGE.contains(&mtu) {
+            return Err(ZTError {
+                code: 120i32,
+                message: format!(
+                    "mtu {} out of range {}-{}",
+                    mtu,
+                    MTU_RANGE.start(),
+                    MTU_RANGE.end()
+                ),
+            });
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /// Every capability and tag the member has must be defined here, the
+    /// controller would silently ignore the others
+    pub fn check_member(&self, m: &Member) -> Result<(), ZTError> {
+        if let Some(c) = m.capabilities.iter().find(|&&c| self.capability(c).is_none()) {
+            return Err(ZTError {
+                code: 161i32,
+                message: format!("capability {} is not defined on the network", c),
+            });
+        }
+        if let Some((t, _)) = m.tags.iter().find(|(t, _)| self.tag(*t).is_none()) {
+            return Err(ZTError {
+                code: 162i32,
+                message: format!("tag {} is not defined on the network", t),
+            });
+        }
+        Ok(())
+    }
+
+    /// Is `s`-`e` a pool that `s/m` can carry? Both ends of the same family,
+    /// in order, inside the subnet, and (for IPv4) clear of its network and
+    /// broadcast address. Hands back the subnet, for the carrying route.
+    pub fn validate_pool(s: IpAddr, e: IpAddr, m: u8) -> Result<IpNet, ZTError> {
+        let invalid = |code: i32, why: &str| ZTError {
+            code,
+            message: format!("pool {}-{} /{}: {}", s, e, m, why),
+        };
+        if s.is_ipv4() != e.is_ipv4() {
+            return Err(invalid(113, "start and end are not the same family"));
+        }
+        if s > e {
+            return Err(invalid(114, "start comes after end"));
+        }
+        let subnet = match RootInterface::new_ipnet(s, m) {
+            Ok(n) => n.trunc(),
+            Err(_) => return Err(invalid(107, "invalid mask")),
+        };
+        if !subnet.contains(&e) {
+            return Err(invalid(115, &format!("end is outside of {}", subnet)));
+        }
+        // a /31 or /32 has no network nor broadcast address to avoid
+        if s.is_ipv4() && m < 31 && (s == subnet.network() || e == subnet.broadcast()) {
+            return Err(invalid(116, "the network or broadcast address is in the range"));
+        }
+        Ok(subnet)
+    }
+
+    /// Build a network, see `RootInterfaceBuilder`
+    pub fn builder() -> RootInterfaceBuilder {
+        RootInterfaceBuilder::default()
+    }
 
     /// We create a rootinterface with some reasonable defaults,
-    /// with private = true
+    /// with private = true. The pool is validated, see `validate_pool`.
     pub fn with(
         n: Option<String>,
         p: bool,
         s: IpAddr,
         e: IpAddr,
         m: u8,
-        nwid: Option<String>,
-    ) -> Self {
-        let subnet = RootInterface::new_ipnet(s, m);
+        nwid: Option<NetworkId>,
+    ) -> Result<Self, ZTError> {
+        let subnet = RootInterface::validate_pool(s, e, m)?;
 
         // yeah baby !!
         let route = Routes {
-            target: subnet.unwrap(),
+            target: subnet,
             ..Default::default()
         };
 
@@ -207,48 +603,449 @@ impl RootInterface {
 
         let mut r: RootInterface = RootInterface::default();
         if nwid.is_some() {
-            r.nwid = nwid.to_owned();
+            r.nwid = nwid;
         }
         r.name = n;
         r.private = p;
         r.ip_assignment_pools = vec![pool];
         r.routes = vec![route];
-        r
+        Ok(r)
     }
 
     // end RootInterface
 }
 
+/// An address as a user typed it
+pub fn parse_addr(a: &str) -> Result<IpAddr, ZTError> {
+    a.trim()
+        .parse()
+        .map_err(|_| ZTError::new(117, &format!("invalid address {}", a)))
+}
+
+/// A mask as a user typed it, with or without the leading `/`
+pub fn parse_mask(m: &str) -> Result<u8, ZTError> {
+    m.trim()
+        .trim_start_matches('/')
+        .parse()
+        .map_err(|_| ZTError::new(107, &format!("invalid mask {}", m)))
+}
+
+/// The net `a/m`, from text as a user typed it
+pub fn parse_net(a: &str, m: &str) -> Result<IpNet, ZTError> {
+    let (addr, mask) = (parse_addr(a)?, parse_mask(m)?);
+    RootInterface::new_ipnet(addr, mask).map_err(|_| ZTError::new(107, &format!("invalid mask {} for {}", m, a)))
+}
+
+/// Builds a `RootInterface` from the defaults. Nothing is checked until
+/// `build`, which reports every problem at once: the code is that of the
+/// first, the message has them all. Without pools the network keeps the
+/// default pool and route.
+#[derive(Debug, Default)]
+pub struct RootInterfaceBuilder {
+    name: Option<String>,
+    private: Option<bool>,
+    nwid: Option<NetworkId>,
+    pools: Vec<(IpAddr, IpAddr, u8)>,
+    v4_assign_mode: Option<V4AssignMode>,
+    v6_assign_mode: Option<V6AssignMode>,
+    problems: Vec<ZTError>,
+}
+
+impl RootInterfaceBuilder {
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = Some(private);
+        self
+    }
+
+    pub fn nwid(mut self, nwid: NetworkId) -> Self {
+        self.nwid = Some(nwid);
+        self
+    }
+
+    pub fn v4_assign_mode(mut self, mode: V4AssignMode) -> Self {
+        self.v4_assign_mode = Some(mode);
+        self
+    }
+
+    /// Set as given, even when an IPv6 pool would switch on `zt`
+    pub fn v6_assign_mode(mut self, mode: V6AssignMode) -> Self {
+        self.v6_assign_mode = Some(mode);
+        self
+    }
+
+    /// `v4_assign_mode`, from text as a user typed it
+    pub fn parse_v4_assign_mode(mut self, mode: &str) -> Self {
+        match mode.parse() {
+            Ok(m) => self.v4_assign_mode = Some(m),
+            Err(e) => self.problems.push(e),
+        }
+        self
+    }
+
+    /// `v6_assign_mode`, from text as a user typed it
+    pub fn parse_v6_assign_mode(mut self, mode: &str) -> Self {
+        match mode.parse() {
+            Ok(m) => self.v6_assign_mode = Some(m),
+            Err(e) => self.problems.push(e),
+        }
+        self
+    }
+
+    /// A pool from `s` to `e`, carried by the route to `s/m`
+    pub fn pool(mut self, s: IpAddr, e: IpAddr, m: u8) -> Self {
+        self.pools.push((s, e, m));
+        self
+    }
+
+    /// `pool`, from text as a user typed it
+    pub fn parse_pool(mut self, s: &str, e: &str, m: &str) -> Self {
+        let (s, e, m) = (parse_addr(s), parse_addr(e), parse_mask(m));
+        match (s, e, m) {
+            (Ok(s), Ok(e), Ok(m)) => self.pools.push((s, e, m)),
+            (s, e, m) => {
+                self.problems.extend(s.err());
+                self.problems.extend(e.err());
+                self.problems.extend(m.err());
+            }
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<RootInterface, ZTError> {
+        let mut problems = self.problems;
+        let mut r = RootInterface::default();
+        if self.name.is_some() {
+            r.name = self.name;
+        }
+        if let Some(p) = self.private {
+            r.private = p;
+        }
+        r.nwid = self.nwid;
+        if !self.pools.is_empty() {
+            r.ip_assignment_pools.clear();
+            r.routes.clear();
+        }
+        for (s, e, m) in self.pools {
+            if let Err(err) = r.add_subnet(s, e, m) {
+                problems.push(err);
+            }
+        }
+        if let Some(m) = self.v4_assign_mode {
+            r.v4_assign_mode = m;
+        }
+        if let Some(m) = self.v6_assign_mode {
+            r.v6_assign_mode = m;
+        }
+        match problems.first() {
+            None => Ok(r),
+            Some(first) => Err(ZTError {
+                code: first.code,
+                message: problems
+                    .iter()
+                    .map(|p| p.message.as_str())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            }),
+        }
+    }
+}
+
 /// Zerotier's notion for rules in this format is for 1.2.x clients,
 /// We'll adhere to **only** that.
 ///
 /// This means that nodes (clients) that are version <1.2,  
 /// won't be able to forward packets in the nets managed
 /// by this controller
-#[derive(Default, Debug, Serialize, Deserialize)]
+///
+/// One entry of a rule set: the rule, and whether it's negated and or-ed
+/// with the match before it. The controller writes `not` and `or` on every
+/// entry, actions included, so we do too.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Rules {
-    #[serde(rename = "etherType")]
-    pub ethtype: u16,
-    #[serde(rename = "not")]
+    #[serde(rename = "not", default)]
     pub rnot: bool,
-    #[serde(rename = "or")]
+    #[serde(rename = "or", default)]
     pub ror: bool,
-    #[serde(rename = "type")]
-    pub rtype: String,
+    #[serde(flatten, with = "rule_or_unknown")]
+    pub rule: Rule,
+}
+
+/// A rule of a type we don't know becomes `Rule::Unknown`, one of a type we
+/// know still has to have the right fields
+mod rule_or_unknown {
+    use super::Rule;
+    use serde::{Deserialize, Serialize};
+
+    pub fn serialize<S: serde::Serializer>(r: &Rule, s: S) -> Result<S::Ok, S::Error> {
+        match r {
+            Rule::Unknown(v) => v.serialize(s),
+            r => r.serialize(s),
+        }
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Rule, D::Error> {
+        let v = serde_json::Value::deserialize(d)?;
+        match v["type"].as_str() {
+            Some(t) if !Rule::TYPES.contains(&t) => Ok(Rule::Unknown(v)),
+            _ => Rule::deserialize(&v).map_err(serde::de::Error::custom),
+        }
+    }
 }
 
 impl Rules {
     /// Create a rule
-    pub fn with(e: u16, n: bool, o: bool, t: String) -> Self {
+    pub fn with(rule: Rule, n: bool, o: bool) -> Self {
         Rules {
-            ethtype: e,
             rnot: n,
             ror: o,
-            rtype: t,
+            rule,
+        }
+    }
+
+    /// The rule as is, not negated nor or-ed
+    pub fn new(rule: Rule) -> Self {
+        Rules::with(rule, false, false)
+    }
+}
+
+/// Every rule type the controller knows, with the fields it has for it.
+/// Actions end a rule, the matches before them decide whether it applies.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Rule {
+    ActionDrop,
+    ActionAccept,
+    /// Stop evaluating this rule set, and go on with the capabilities
+    ActionBreak,
+    /// Send a copy of (the first `length` bytes of) the packet to `address`
+    ActionTee {
+        address: NodeAddress,
+        flags: u32,
+        length: u16,
+    },
+    /// Like tee, with the verdict of the receiving side attached
+    ActionWatch {
+        address: NodeAddress,
+        flags: u32,
+        length: u16,
+    },
+    /// Send the packet to `address` instead
+    ActionRedirect {
+        address: NodeAddress,
+        flags: u32,
+        length: u16,
+    },
+    ActionDebugLog,
+    MatchSourceZerotierAddress {
+        zt: NodeAddress,
+    },
+    MatchDestZerotierAddress {
+        zt: NodeAddress,
+    },
+    MatchVlanId {
+        #[serde(rename = "vlanId")]
+        vlan_id: u16,
+    },
+    MatchVlanPcp {
+        #[serde(rename = "vlanPcp")]
+        vlan_pcp: u8,
+    },
+    MatchVlanDei {
+        #[serde(rename = "vlanDei")]
+        vlan_dei: u8,
+    },
+    /// `xx:xx:xx:xx:xx:xx`
+    MatchMacSource {
+        mac: String,
+    },
+    MatchMacDest {
+        mac: String,
+    },
+    MatchIpv4Source {
+        ip: Ipv4Net,
+    },
+    MatchIpv4Dest {
+        ip: Ipv4Net,
+    },
+    MatchIpv6Source {
+        ip: Ipv6Net,
+    },
+    MatchIpv6Dest {
+        ip: Ipv6Net,
+    },
+    /// The TOS byte, masked, within `start` and `end`
+    MatchIpTos {
+        mask: u8,
+        start: u8,
+        end: u8,
+    },
+    MatchIpProtocol {
+        #[serde(rename = "ipProtocol")]
+        ip_protocol: u8,
+    },
+    MatchEthertype {
+        #[serde(rename = "etherType")]
+        ether_type: u16,
+    },
+    /// A `None` code matches any code of the type
+    MatchIcmp {
+        #[serde(rename = "icmpType")]
+        icmp_type: u8,
+        #[serde(rename = "icmpCode")]
+        icmp_code: Option<u8>,
+    },
+    MatchIpSourcePortRange {
+        start: u16,
+        end: u16,
+    },
+    MatchIpDestPortRange {
+        start: u16,
+        end: u16,
+    },
+    /// Any of the bits of `mask` (see the rules module for their names)
+    MatchCharacteristics {
+        #[serde(with = "hex_u64")]
+        mask: u64,
+    },
+    MatchFrameSizeRange {
+        start: u16,
+        end: u16,
+    },
+    /// Matches with a chance of `probability` / `u32::MAX`
+    MatchRandom {
+        probability: u32,
+    },
+    MatchTagsDifference {
+        id: u32,
+        value: u32,
+    },
+    MatchTagsBitwiseAnd {
+        id: u32,
+        value: u32,
+    },
+    MatchTagsBitwiseOr {
+        id: u32,
+        value: u32,
+    },
+    MatchTagsBitwiseXor {
+        id: u32,
+        value: u32,
+    },
+    MatchTagsEqual {
+        id: u32,
+        value: u32,
+    },
+    MatchTagSender {
+        id: u32,
+        value: u32,
+    },
+    MatchTagReceiver {
+        id: u32,
+        value: u32,
+    },
+    /// `bits` bits at byte `idx` of the packet, read as an integer
+    MatchIntegerRange {
+        #[serde(with = "hex_u64")]
+        start: u64,
+        #[serde(with = "hex_u64")]
+        end: u64,
+        idx: u16,
+        little: bool,
+        bits: u8,
+    },
+    /// Put the packet in QoS bucket `qos_bucket` (0 to 8) and accept it
+    ActionPriority {
+        #[serde(rename = "qosBucket")]
+        qos_bucket: u8,
+    },
+    /// A rule type we don't know, kept as the controller sent it so that
+    /// it goes back unchanged. Only `Rules` reads and writes it.
+    #[serde(skip)]
+    Unknown(serde_json::Value),
+}
+
+impl Rule {
+    /// Actions end a rule, everything else is a match
+    pub fn is_action(&self) -> bool {
+        matches!(
+            self,
+            Rule::ActionDrop
+                | Rule::ActionAccept
+                | Rule::ActionBreak
+                | Rule::ActionTee { .. }
+                | Rule::ActionWatch { .. }
+                | Rule::ActionRedirect { .. }
+                | Rule::ActionDebugLog
+                | Rule::ActionPriority { .. }
+        ) || self.unknown_type().is_some_and(|t| t.starts_with("ACTION_"))
+    }
+
+    /// The `type` of every rule we know, as the controller spells it
+    pub const TYPES: [&'static str; 36] = [
+        "ACTION_DROP",
+        "ACTION_ACCEPT",
+        "ACTION_BREAK",
+        "ACTION_TEE",
+        "ACTION_WATCH",
+        "ACTION_REDIRECT",
+        "ACTION_DEBUG_LOG",
+        "MATCH_SOURCE_ZEROTIER_ADDRESS",
+        "MATCH_DEST_ZEROTIER_ADDRESS",
+        "MATCH_VLAN_ID",
+        "MATCH_VLAN_PCP",
+        "MATCH_VLAN_DEI",
+        "MATCH_MAC_SOURCE",
+        "MATCH_MAC_DEST",
+        "MATCH_IPV4_SOURCE",
+        "MATCH_IPV4_DEST",
+        "MATCH_IPV6_SOURCE",
+        "MATCH_IPV6_DEST",
+        "MATCH_IP_TOS",
+        "MATCH_IP_PROTOCOL",
+        "MATCH_ETHERTYPE",
+        "MATCH_ICMP",
+        "MATCH_IP_SOURCE_PORT_RANGE",
+        "MATCH_IP_DEST_PORT_RANGE",
+        "MATCH_CHARACTERISTICS",
+        "MATCH_FRAME_SIZE_RANGE",
+        "MATCH_RANDOM",
+        "MATCH_TAGS_DIFFERENCE",
+        "MATCH_TAGS_BITWISE_AND",
+        "MATCH_TAGS_BITWISE_OR",
+        "MATCH_TAGS_BITWISE_XOR",
+        "MATCH_TAGS_EQUAL",
+        "MATCH_TAG_SENDER",
+        "MATCH_TAG_RECEIVER",
+        "MATCH_INTEGER_RANGE",
+        "ACTION_PRIORITY",
+    ];
+
+    /// The `type` of a rule we don't know
+    pub fn unknown_type(&self) -> Option<&str> {
+        match self {
+            Rule::Unknown(v) => v["type"].as_str(),
+            _ => None,
         }
     }
 }
 
+/// The controller writes 64-bit masks and integers as 16 hex digits
+mod hex_u64 {
+    pub fn serialize<S: serde::Serializer>(v: &u64, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{:016x}", v))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(d)?;
+        u64::from_str_radix(&s, 16).map_err(serde::de::Error::custom)
+    }
+}
+
 /// Every net has at least one route, the one that holds the IpAssignmentPool
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Routes {
@@ -270,6 +1067,86 @@ impl Default for Routes {
     }
 }
 
+/// What can be wrong with a route table. Routes and pools are referred to
+/// by their index in `RootInterface.routes` and `ip_assignment_pools`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteProblem {
+    /// The gateway is not inside any carrying (via-less) route
+    NoCarryingNet { route: usize, via: IpAddr },
+    /// The gateway lives in the very net it should route to
+    GatewayInOwnTarget { route: usize, via: IpAddr },
+    /// Same target as an earlier route
+    DuplicateTarget { route: usize, first: usize },
+    /// An IPv4 gateway for an IPv6 net, or the other way around
+    MixedFamilies { route: usize, via: IpAddr },
+    /// No carrying route holds the whole pool
+    UncarriedPool { pool: usize },
+}
+
+impl RouteProblem {
+    pub fn code(&self) -> i32 {
+        match self {
+            RouteProblem::NoCarryingNet { .. } => 101,
+            RouteProblem::GatewayInOwnTarget { .. } => 109,
+            RouteProblem::DuplicateTarget { .. } => 110,
+            RouteProblem::MixedFamilies { .. } => 111,
+            RouteProblem::UncarriedPool { .. } => 112,
+        }
+    }
+}
+
+impl std::fmt::Display for RouteProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RouteProblem::NoCarryingNet { route, via } => {
+                write!(f, "route {}: no carrying net for gw {}", route, via)
+            }
+            RouteProblem::GatewayInOwnTarget { route, via } => {
+                write!(f, "route {}: gw {} is inside its own target", route, via)
+            }
+            RouteProblem::DuplicateTarget { route, first } => {
+                write!(f, "route {}: same target as route {}", route, first)
+            }
+            RouteProblem::MixedFamilies { route, via } => {
+                write!(f, "route {}: gw {} is not of the target's family", route, via)
+            }
+            RouteProblem::UncarriedPool { pool } => {
+                write!(f, "pool {}: no carrying route holds the range", pool)
+            }
+        }
+    }
+}
+
+/// Everything `RootInterface::check_routes` found, empty when all is well
+#[derive(Debug, Default)]
+pub struct RouteReport {
+    pub problems: Vec<RouteProblem>,
+}
+
+impl RouteReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl std::fmt::Display for RouteReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let lines: Vec<String> = self.problems.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}", lines.join("; "))
+    }
+}
+
+/// A report becomes a single error with the code of its first problem, the
+/// message lists them all.
+impl From<RouteReport> for ZTError {
+    fn from(r: RouteReport) -> Self {
+        ZTError {
+            code: r.problems.first().map(|p| p.code()).unwrap_or(102i32),
+            message: r.to_string(),
+        }
+    }
+}
+
 impl Routes {
     /// Create a new route entry
     pub fn with(&mut self, target: IpNet, gw: Option<IpAddr>) {
@@ -288,44 +1165,613 @@ impl Routes {
     }
 }
 
+/// A capability, rules of its own that are evaluated for the members it's
+/// granted to, after the network's rules leave the packet undecided.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capability {
+    pub id: u32,
+    #[serde(default)]
+    pub rules: Vec<Rules>,
+}
+
+/// A tag members can carry a value for, `default` for those that don't
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TagDefinition {
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<u32>,
+}
+
+/// A node that joined (or tried to join) a network, as the controller keeps
+/// it under `/controller/network/<nwid>/member/<id>`.
+/// Tags are `[id, value]` pairs, capabilities a list of capability ids.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Member {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<NodeAddress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nwid: Option<NetworkId>,
+    #[serde(default)]
+    pub authorized: bool,
+    #[serde(rename = "activeBridge", default)]
+    pub active_bridge: bool,
+    #[serde(rename = "ipAssignments", default)]
+    pub ip_assignments: Vec<IpAddr>,
+    #[serde(default)]
+    pub tags: Vec<(u32, u32)>,
+    #[serde(default)]
+    pub capabilities: Vec<u32>,
+    #[serde(default)]
+    pub revision: u64,
+    #[serde(rename = "noAutoAssignIps", default)]
+    pub no_auto_assign_ips: bool,
+}
+
+impl Member {
+    /// Grant a capability, once
+    pub fn grant_capability(&mut self, id: u32) {
+        if !self.capabilities.contains(&id) {
+            self.capabilities.push(id);
+        }
+    }
+
+    pub fn revoke_capability(&mut self, id: u32) {
+        self.capabilities.retain(|&c| c != id);
+    }
+
+    /// Set the member's value of a tag, replacing the one it had
+    pub fn set_tag(&mut self, id: u32, value: u32) {
+        match self.tags.iter_mut().find(|(t, _)| *t == id) {
+            Some(t) => t.1 = value,
+            None => self.tags.push((id, value)),
+        }
+    }
+
+    pub fn unset_tag(&mut self, id: u32) {
+        self.tags.retain(|(t, _)| *t != id);
+    }
+}
+
+/// The zerotier home directories we look in, in order: Linux, macOS and
+/// the 0-OS local daemon.
+pub const ZT_HOMES: [&str; 3] = [
+    "/var/lib/zerotier-one",
+    "/Library/Application Support/ZeroTier/One",
+    "/tmp/zt",
+];
+
+/// Why we could not come up with an `Auth`, naming the place that failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    /// A file we were told to use, or that exists, can't be read
+    Unreadable { location: String, reason: String },
+    /// The token at `location` is empty
+    EmptyToken { location: String },
+    /// `identity.public` is not `address:0:pubkey`
+    BadIdentity { location: String },
+    /// None of the locations had a token, with the reason for each
+    NoToken { tried: Vec<(String, String)> },
+}
+
+impl AuthError {
+    pub fn code(&self) -> i32 {
+        match self {
+            AuthError::Unreadable { .. } => 131,
+            AuthError::EmptyToken { .. } => 132,
+            AuthError::BadIdentity { .. } => 133,
+            AuthError::NoToken { .. } => 134,
+        }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::Unreadable { location, reason } => {
+                write!(f, "cannot read {}: {}", location, reason)
+            }
+            AuthError::EmptyToken { location } => write!(f, "empty auth token in {}", location),
+            AuthError::BadIdentity { location } => {
+                write!(f, "{} is not an address:0:pubkey identity", location)
+            }
+            AuthError::NoToken { tried } => {
+                let tried: Vec<String> =
+                    tried.iter().map(|(l, r)| format!("{} ({})", l, r)).collect();
+                write!(f, "no auth token found, tried {}", tried.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<AuthError> for ZTError {
+    fn from(e: AuthError) -> Self {
+        ZTError {
+            code: e.code(),
+            message: e.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Auth {
-  pub serverid: Option<String>,
+  pub serverid: Option<NodeAddress>,
   pub auth_token: String,
 }
 
 impl Auth {
-  /// Reads the serverid and local auth for the network
-  /// If we want to control the 0-OS local daemon, we read in `/tmp/zt`
-  pub fn read_auth() -> Result<Self,Error>{
-    let srvstr: String = std::fs::read_to_string("/var/lib/zerotier-one/identity.public")?;
-    let token: String = std::fs::read_to_string("/home/delandtj/.zeroTierOneAuthToken")?;
-
-//    let srvstr: String = match std::fs::read_to_string("/var/lib/zerotier-one/identity.public"){
-//        Ok(srv) => srv,
-//        Err(_) => "boo".to_owned(),
-//    };
-//
-//    let token: String = match std::fs:read_to_string("/var/lib/zerotier-one/authtoken.public"){
-//        Ok(tok) => tok,
-//        Err(_) => "boo".to_owned(),
-//    };
-
-    let serverid = 
-        Ok(Auth {
-        serverid: Some(String::from(&srvstr[1..10])),
-        auth_token: String::from(&token[..]),
-        });
-    serverid
+  /// Finds the auth token and serverid the way zerotier-cli does, see
+  /// `discover`
+  pub fn read_auth() -> Result<Self, AuthError> {
+    Auth::discover(None)
+  }
+
+  /// Takes the first token of: the `token` file if given, the `ZT_TOKEN`
+  /// env var, `~/.zeroTierOneAuthToken` and `authtoken.secret` in each of
+  /// `ZT_HOMES`. The serverid comes from the `identity.public` next to the
+  /// token file, so token and identity are of the same daemon; it stays
+  /// `None` for `ZT_TOKEN`, `~/.zeroTierOneAuthToken` or a home without one.
+  pub fn discover(token: Option<&std::path::Path>) -> Result<Self, AuthError> {
+    let user_home = std::env::var_os("HOME").map(std::path::PathBuf::from);
+    let homes: Vec<std::path::PathBuf> = ZT_HOMES.iter().map(std::path::PathBuf::from).collect();
+    Auth::discover_in(token, std::env::var("ZT_TOKEN").ok(), user_home.as_deref(), &homes)
+  }
+
+  fn discover_in(
+    token: Option<&std::path::Path>,
+    env_token: Option<String>,
+    user_home: Option<&std::path::Path>,
+    homes: &[std::path::PathBuf],
+  ) -> Result<Self, AuthError> {
+    let (auth_token, home) = match (token, env_token) {
+      // asked for explicitly, so no falling back
+      (Some(path), _) => (read_token(path)?, path.parent()),
+      (None, Some(t)) => {
+        if t.trim().is_empty() {
+          return Err(AuthError::EmptyToken { location: "ZT_TOKEN".to_owned() });
+        }
+        (t.trim().to_owned(), None)
+      }
+      (None, None) => {
+        let mut candidates = Vec::new();
+        if let Some(h) = user_home {
+          candidates.push((h.join(".zeroTierOneAuthToken"), None));
+        }
+        candidates.extend(homes.iter().map(|h| (h.join("authtoken.secret"), Some(h.as_path()))));
+        let mut tried = Vec::new();
+        let mut found = None;
+        for (c, home) in candidates {
+          match read_token(&c) {
+            Ok(t) => {
+              found = Some((t, home));
+              break;
+            }
+            // a non-root user can't read the daemon's token, that's no
+            // reason to stop looking
+            Err(AuthError::Unreadable { location, reason }) => tried.push((location, reason)),
+            Err(e) => return Err(e),
+          }
+        }
+        match found {
+          Some(f) => f,
+          None => return Err(AuthError::NoToken { tried }),
+        }
+      }
+    };
+
+    let serverid = match home.map(|h| h.join("identity.public")) {
+      Some(identity) if identity.exists() => Some(read_serverid(&identity)?),
+      _ => None,
+    };
+    Ok(Auth { serverid, auth_token })
+  }
+
+  /// Same as `read_auth`, from an identity and token file of your choice
+  pub fn read_auth_from(identity: &std::path::Path, token: &std::path::Path) -> Result<Self, AuthError> {
+    Ok(Auth {
+      serverid: Some(read_serverid(identity)?),
+      auth_token: read_token(token)?,
+    })
+  }
+}
+
+fn read_file(path: &std::path::Path) -> Result<String, AuthError> {
+  std::fs::read_to_string(path).map_err(|e| AuthError::Unreadable {
+    location: path.display().to_string(),
+    reason: e.to_string(),
+  })
+}
+
+fn read_token(path: &std::path::Path) -> Result<String, AuthError> {
+  let token = read_file(path)?;
+  if token.trim().is_empty() {
+    return Err(AuthError::EmptyToken { location: path.display().to_string() });
+  }
+  Ok(token.trim().to_owned())
+}
+
+/// The serverid is the address, the first field of `address:0:pubkey`
+fn read_serverid(path: &std::path::Path) -> Result<NodeAddress, AuthError> {
+  let identity = read_file(path)?;
+  match identity.parse::<Identity>() {
+    Ok(id) => Ok(id.address()),
+    Err(_) => Err(AuthError::BadIdentity { location: path.display().to_string() }),
   }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use failure::Error;
+    const PUBLIC: &str = "3b2caa9d5bd3a58aa7a36d4bc1e1b4b3d2da9b7a6c5b1f7f4e5d0c5e2a1d3f4c\
+                          0a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f9";
+
     #[test]
     fn test_auth()-> Result<(),Error> {
-        let auth_data: Auth = Auth::read_auth()?;
+        let dir = std::env::temp_dir().join(format!("ztproxy-auth-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("identity.public"), format!("8056c2e21c:0:{}\n", PUBLIC))?;
+        std::fs::write(dir.join("authtoken.secret"), "sikrit\n")?;
+        let auth_data: Auth =
+            Auth::read_auth_from(&dir.join("identity.public"), &dir.join("authtoken.secret"))?;
         println!("{:?}  --  {:?}",auth_data.auth_token, auth_data.serverid);
+        assert_eq!(auth_data.auth_token, "sikrit");
+        assert_eq!(auth_data.serverid, Some("8056c2e21c".parse()?));
+
+        std::fs::write(dir.join("identity.public"), "8056c2e21c:1:3b2caa9d")?;
+        let e = Auth::read_auth_from(&dir.join("identity.public"), &dir.join("authtoken.secret"))
+            .unwrap_err();
+        assert_eq!(e.code(), 133);
+        std::fs::remove_dir_all(&dir)?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_discover_auth() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("ztproxy-discover-{}", std::process::id()));
+        let (user, linux, zos) = (dir.join("user"), dir.join("linux"), dir.join("zos"));
+        for d in &[&user, &linux, &zos] {
+            std::fs::create_dir_all(d)?;
+        }
+        let homes = vec![linux.clone(), zos.clone()];
+
+        let e = Auth::discover_in(None, None, Some(&user), &homes).unwrap_err();
+        assert_eq!(e.code(), 134);
+        assert!(e.to_string().contains(".zeroTierOneAuthToken"));
+
+        // only the 0-OS daemon runs, and the identity comes from its home
+        std::fs::write(zos.join("authtoken.secret"), "zos")?;
+        std::fs::write(zos.join("identity.public"), format!("0123456789:0:{}", PUBLIC))?;
+        let a = Auth::discover_in(None, None, Some(&user), &homes)?;
+        assert_eq!((a.auth_token.as_str(), a.serverid), ("zos", Some("0123456789".parse()?)));
+
+        // the identity of another daemon never goes with the token
+        std::fs::write(linux.join("identity.public"), format!("fedcba9876:0:{}", PUBLIC))?;
+        let a = Auth::discover_in(None, None, Some(&user), &homes)?;
+        assert_eq!((a.auth_token.as_str(), a.serverid), ("zos", Some("0123456789".parse()?)));
+
+        std::fs::write(user.join(".zeroTierOneAuthToken"), "mine")?;
+        let a = Auth::discover_in(None, None, Some(&user), &homes)?;
+        assert_eq!((a.auth_token.as_str(), a.serverid), ("mine", None));
+        let a = Auth::discover_in(None, Some("env".to_owned()), Some(&user), &homes)?;
+        assert_eq!((a.auth_token.as_str(), a.serverid), ("env", None));
+        let a = Auth::discover_in(Some(&zos.join("authtoken.secret")), Some("env".to_owned()), None, &homes)?;
+        assert_eq!((a.auth_token.as_str(), a.serverid), ("zos", Some("0123456789".parse()?)));
+
+        // an explicit path that isn't there doesn't fall back
+        let e = Auth::discover_in(Some(&dir.join("nope")), None, Some(&user), &homes).unwrap_err();
+        assert_eq!(e.code(), 131);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_subnet() -> Result<(), Error> {
+        let mut r = RootInterface::with(
+            None,
+            true,
+            "10.10.10.10".parse()?,
+            "10.10.10.100".parse()?,
+            24,
+            None,
+        )?;
+        // overlaps the pool we start with
+        assert_eq!(
+            r.add_subnet("10.10.10.50".parse()?, "10.10.10.200".parse()?, 24)
+                .unwrap_err()
+                .code(),
+            106
+        );
+        r.add_subnet("fdab:1234::1:1".parse()?, "fdab:1234::f:ff00".parse()?, 64)?;
+        assert_eq!(r.ip_assignment_pools.len(), 2);
+        assert_eq!(r.routes[1].target, "fdab:1234::/64".parse::<IpNet>()?);
+        assert!(r.v6_assign_mode.zt);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_pool() -> Result<(), Error> {
+        let pool = |s: &str, e: &str, m| RootInterface::validate_pool(s.parse().unwrap(), e.parse().unwrap(), m);
+        assert_eq!(pool("10.1.1.10", "10.1.1.20", 24)?, "10.1.1.0/24".parse::<IpNet>()?);
+        assert_eq!(pool("10.1.1.0", "10.1.1.1", 31)?, "10.1.1.0/31".parse::<IpNet>()?);
+        pool("fd00::", "fd00::ffff", 64)?;
+        for (s, e, m, code) in &[
+            ("10.1.1.10", "fd00::1", 24, 113),
+            ("10.1.1.20", "10.1.1.10", 24, 114),
+            ("10.1.1.10", "10.1.1.20", 33, 107),
+            ("10.1.1.10", "10.1.2.20", 24, 115),
+            ("10.1.1.0", "10.1.1.20", 24, 116),
+            ("10.1.1.10", "10.1.1.255", 24, 116),
+        ] {
+            assert_eq!(pool(s, e, *m).unwrap_err().code(), *code, "{}-{}/{}", s, e, m);
+        }
+        let r = RootInterface::with(None, true, "10.1.1.10".parse()?, "10.1.2.1".parse()?, 24, None);
+        assert_eq!(r.unwrap_err().code(), 115);
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder() -> Result<(), Error> {
+        let r = RootInterface::builder()
+            .name("built")
+            .parse_pool("10.1.1.10", "10.1.1.20", "24")
+            .pool("fd00::1".parse()?, "fd00::ff".parse()?, 64)
+            .build()?;
+        assert_eq!(r.name.as_deref(), Some("built"));
+        assert!(r.private);
+        assert_eq!(r.ip_assignment_pools.len(), 2);
+        assert_eq!(r.routes[0].target, "10.1.1.0/24".parse::<IpNet>()?);
+        assert!(r.v6_assign_mode.zt);
+        assert_eq!(RootInterface::builder().build()?.ip_assignment_pools.len(), 1);
+
+        let e = RootInterface::builder()
+            .parse_pool("10.1.1.1O", "10.1.1.20", "/24")
+            .parse_pool("10.2.1.10", "10.2.1.20", "x")
+            .parse_pool("10.3.1.20", "10.3.1.10", "24")
+            .build()
+            .unwrap_err();
+        assert_eq!(e.code(), 117);
+        assert!(e.to_string().contains("10.1.1.1O"));
+        assert!(e.to_string().contains("invalid mask x"));
+        assert!(e.to_string().contains("start comes after end"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_assign_modes() -> Result<(), Error> {
+        let m: V6AssignMode = serde_json::from_str(r#"{"zt":false,"6plane":true,"rfc4193":true}"#)?;
+        assert!(m.sixplane && m.rfc4193 && !m.zt);
+        assert_eq!(serde_json::from_str::<V6AssignMode>(r#""zt,6plane""#)?, "6plane,zt".parse()?);
+        assert_eq!(serde_json::from_str::<V4AssignMode>(r#""none""#)?, V4AssignMode { zt: false });
+        assert_eq!(
+            serde_json::to_string(&m)?,
+            r#"{"zt":false,"6plane":true,"rfc4193":true}"#
+        );
+        assert_eq!("none".parse::<V6AssignMode>()?, V6AssignMode::default());
+        assert_eq!("zt,6plane".parse::<V4AssignMode>().unwrap_err().code(), 119);
+
+        let r = RootInterface::builder()
+            .v6_assign_mode("rfc4193".parse()?)
+            .build()?;
+        assert!(r.v4_assign_mode.zt && r.v6_assign_mode.rfc4193);
+        let e = RootInterface::builder()
+            .parse_v4_assign_mode("6plane")
+            .parse_pool("10.1.1.1", "10.1.1.2", "33")
+            .build()
+            .unwrap_err();
+        assert_eq!(e.code(), 119);
+        assert!(e.to_string().contains("invalid mask"));
+
+        // a network that doesn't say still gets its addresses from the pools
+        let r: RootInterface = serde_json::from_str(
+            r#"{"name":"n","private":true,"id":null,"nwid":null,"auth":null,
+                "routes":[],"ipAssignmentPools":[],"rules":[]}"#,
+        )?;
+        assert_eq!(r.v4_assign_mode, RootInterface::default().v4_assign_mode);
+        assert!(V4AssignMode::default().zt);
+        Ok(())
+    }
+
+    #[test]
+    fn test_settings() -> Result<(), Error> {
+        let r: RootInterface = serde_json::from_str(
+            r#"{"name":"n","private":true,"id":null,"nwid":null,"auth":null,
+                "routes":[],"ipAssignmentPools":[],"rules":[]}"#,
+        )?;
+        assert!(r.enable_broadcast && !r.allow_passive_bridging);
+        assert_eq!((r.multicast_limit, r.mtu), (32, 2800));
+        assert_eq!(r.dns, Dns::default());
+
+        let mut r = RootInterface::default();
+        assert_eq!(r.set_mtu(1000).unwrap_err().code(), 120);
+        r.set_mtu(1400)?;
+        r.dns = Dns {
+            domain: "zt.example".to_owned(),
+            servers: vec!["10.1.1.1".parse()?],
+        };
+        let v = serde_json::to_value(&r)?;
+        assert_eq!(v["mtu"], 1400);
+        assert_eq!(v["dns"], serde_json::json!({"domain": "zt.example", "servers": ["10.1.1.1"]}));
+        assert_eq!(v["enableBroadcast"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_del_route() -> Result<(), Error> {
+        let mut r = RootInterface::with(
+            None,
+            true,
+            "10.10.10.10".parse()?,
+            "10.10.10.100".parse()?,
+            24,
+            None,
+        )?;
+        let gw = "10.10.10.123".parse()?;
+        assert_eq!(
+            r.add_route("172.22.2.0/24".parse()?, Some("10.10.11.1".parse()?))
+                .unwrap_err()
+                .code(),
+            101
+        );
+        assert_eq!(r.routes.len(), 1);
+        r.add_route("172.22.2.0/24".parse()?, Some(gw))?;
+        // the carrying net can't go while the gateway needs it
+        let carrier = r.routes[0].target;
+        assert_eq!(r.del_route(carrier).unwrap_err().code(), 101);
+        assert_eq!(r.del_route("172.22.2.0/24".parse()?)?.via, Some(gw));
+        assert_eq!(r.routes.len(), 1);
+
+        // a duplicate the network already had doesn't block other changes
+        let dup = r.routes[0].clone();
+        r.routes.push(dup);
+        r.add_route("172.22.2.0/24".parse()?, Some(gw))?;
+        let e = r.add_route("172.22.3.0/24".parse()?, Some("10.10.11.1".parse()?)).unwrap_err();
+        assert_eq!((e.code(), r.routes.len()), (101, 3));
+        let dup = r.routes[0].clone();
+        assert_eq!(r.add_route(dup.target, None).unwrap_err().code(), 110);
+        r.del_route("172.22.2.0/24".parse()?)?;
+        assert_eq!(r.check_routes().problems.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_routes() -> Result<(), Error> {
+        let mut r = RootInterface::with(
+            None,
+            true,
+            "10.10.10.10".parse()?,
+            "10.10.10.100".parse()?,
+            24,
+            None,
+        )?;
+        assert!(r.check_routes().is_ok());
+        let mut route = Routes::default();
+        route.with("172.22.2.0/24".parse()?, Some("172.22.2.1".parse()?));
+        r.routes.push(route.clone());
+        r.routes.push(route);
+        let mut route = Routes::default();
+        route.with("fd00::/64".parse()?, Some("10.10.10.1".parse()?));
+        r.routes.push(route);
+        r.add_subnet("192.168.1.10".parse()?, "192.168.1.20".parse()?, 24)?;
+        r.routes.pop();
+
+        let report = r.check_routes();
+        assert_eq!(
+            report.problems,
+            vec![
+                RouteProblem::GatewayInOwnTarget { route: 1, via: "172.22.2.1".parse()? },
+                RouteProblem::NoCarryingNet { route: 1, via: "172.22.2.1".parse()? },
+                RouteProblem::DuplicateTarget { route: 2, first: 1 },
+                RouteProblem::GatewayInOwnTarget { route: 2, via: "172.22.2.1".parse()? },
+                RouteProblem::NoCarryingNet { route: 2, via: "172.22.2.1".parse()? },
+                RouteProblem::MixedFamilies { route: 3, via: "10.10.10.1".parse()? },
+                RouteProblem::UncarriedPool { pool: 1 },
+            ]
+        );
+        assert_eq!(ZTError::from(report).code(), 109);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rules_round_trip() -> Result<(), Error> {
+        // as a controller hands them out
+        let j = r#"[
+            {"type":"MATCH_ETHERTYPE","not":true,"or":false,"etherType":2048},
+            {"type":"MATCH_IPV4_DEST","not":false,"or":true,"ip":"10.1.0.0/16"},
+            {"type":"MATCH_IPV6_SOURCE","not":false,"or":false,"ip":"fd00::/8"},
+            {"type":"MATCH_IP_TOS","not":false,"or":false,"mask":252,"start":0,"end":8},
+            {"type":"MATCH_IP_PROTOCOL","not":false,"or":false,"ipProtocol":6},
+            {"type":"MATCH_IP_DEST_PORT_RANGE","not":false,"or":false,"start":22,"end":22},
+            {"type":"MATCH_ICMP","not":false,"or":false,"icmpType":8,"icmpCode":null},
+            {"type":"MATCH_CHARACTERISTICS","not":true,"or":false,"mask":"0000000000000012"},
+            {"type":"MATCH_MAC_SOURCE","not":false,"or":false,"mac":"02:00:00:aa:bb:cc"},
+            {"type":"MATCH_TAGS_DIFFERENCE","not":false,"or":false,"id":1000,"value":1},
+            {"type":"MATCH_SOURCE_ZEROTIER_ADDRESS","not":false,"or":false,"zt":"deadbeef00"},
+            {"type":"MATCH_INTEGER_RANGE","not":false,"or":false,"start":"0000000000000001",
+             "end":"00000000000000ff","idx":14,"little":false,"bits":8},
+            {"type":"ACTION_TEE","not":false,"or":false,"address":"deadbeef00","flags":0,"length":128},
+            {"type":"ACTION_REDIRECT","not":false,"or":false,"address":"deadbeef00","flags":0,"length":0},
+            {"type":"ACTION_DROP","not":false,"or":false}
+        ]"#;
+        let v: serde_json::Value = serde_json::from_str(j)?;
+        let rules: Vec<Rules> = serde_json::from_value(v.clone())?;
+        assert_eq!(rules[0].rule, Rule::MatchEthertype { ether_type: ZT_ETHERTYPE_IPV4 });
+        assert!(rules[0].rnot && rules[1].ror);
+        assert_eq!(rules[7].rule, Rule::MatchCharacteristics { mask: 0x12 });
+        assert!(!rules[11].rule.is_action() && rules[12].rule.is_action());
+        assert_eq!(serde_json::to_value(&rules)?, v);
+        assert!(serde_json::from_str::<Rules>(r#"{"type":"MATCH_IPV4_DEST","ip":"fd00::/8"}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_rules() -> Result<(), Error> {
+        let j = r#"{"id":"8056c2e21c000001","nwid":"8056c2e21c000001","name":"n","private":true,
+            "auth":null,"routes":[],"ipAssignmentPools":[],"rules":[
+                {"type":"MATCH_SOMETHING_NEW","not":false,"or":false,"frob":[1,2]},
+                {"type":"ACTION_PRIORITY","not":false,"or":false,"qosBucket":2},
+                {"type":"ACTION_SOMETHING_NEW","not":true,"or":false}
+            ]}"#;
+        let net: RootInterface = serde_json::from_str(j)?;
+        assert_eq!(net.rules[0].rule.unknown_type(), Some("MATCH_SOMETHING_NEW"));
+        assert!(!net.rules[0].rule.is_action());
+        assert_eq!(net.rules[1].rule, Rule::ActionPriority { qos_bucket: 2 });
+        assert!(net.rules[2].rule.is_action() && net.rules[2].rnot);
+
+        let v: serde_json::Value = serde_json::from_str(j)?;
+        assert_eq!(serde_json::to_value(&net)?["rules"], v["rules"]);
+
+        // the list has to keep up with the enum
+        for t in Rule::TYPES.iter() {
+            let e = serde_json::from_value::<Rule>(serde_json::json!({ "type": t })).err();
+            assert!(!e.is_some_and(|e| e.to_string().starts_with("unknown variant")), "{}", t);
+        }
+        assert!(serde_json::from_str::<Rules>(r#"{"type":"ACTION_TEE","address":"nope"}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_member_from_controller() -> Result<(), Error> {
+        let j = r#"{"id":"deadbeef00","nwid":"deadbeef00123456","authorized":true,
+            "activeBridge":false,"ipAssignments":["10.149.0.12"],"tags":[[1000,1]],
+            "capabilities":[2000],"revision":4,"noAutoAssignIps":false,
+            "creationTime":1546300800000,"vMajor":1}"#;
+        let m: Member = serde_json::from_str(j)?;
+        assert!(m.authorized);
+        assert_eq!(m.ip_assignments, vec!["10.149.0.12".parse::<IpAddr>()?]);
+        assert_eq!(m.tags, vec![(1000, 1)]);
+        assert_eq!(m.revision, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_capabilities_and_tags() -> Result<(), Error> {
+        let j = r#"{"id":2000,"rules":[{"type":"ACTION_ACCEPT","not":false,"or":false}]}"#;
+        let cap: Capability = serde_json::from_str(j)?;
+        assert_eq!(cap.rules, vec![Rules::new(Rule::ActionAccept)]);
+        let tag: TagDefinition = serde_json::from_str(r#"{"id":1000}"#)?;
+        assert_eq!(serde_json::to_string(&tag)?, r#"{"id":1000}"#);
+
+        let mut net = RootInterface::default();
+        net.define_capability(cap.clone());
+        net.define_capability(Capability { id: 2000, rules: vec![] });
+        net.define_tag(TagDefinition { id: 1000, default: Some(1) });
+        assert_eq!(net.capabilities.len(), 1);
+        assert!(net.capability(2000).unwrap().rules.is_empty());
+
+        let mut m = Member::default();
+        m.grant_capability(2000);
+        m.grant_capability(2000);
+        m.set_tag(1000, 2);
+        m.set_tag(1000, 3);
+        assert_eq!((m.capabilities.len(), m.tags.clone()), (1, vec![(1000, 3)]));
+        net.check_member(&m)?;
+        m.set_tag(1001, 1);
+        assert_eq!(net.check_member(&m).unwrap_err().code(), 162);
+        m.unset_tag(1001);
+        m.grant_capability(2001);
+        assert_eq!(net.check_member(&m).unwrap_err().code(), 161);
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+//! An in-process stand-in for the zerotier daemon's controller API, so the
+//! client and the cli can be tested without a daemon. Build with the `mock`
+//! feature to get it outside of this crate's own tests.
+//!
+//! It answers `/status`, `/controller`, `/controller/network` and the
+//! member endpoints, wants the auth token like the real one does (header
+//! or `?auth=`), and keeps everything in memory. New networks get the
+//! controller address plus 6 random hex digits, as they would on a daemon.
+
+extern crate rand;
+extern crate tiny_http;
+
+use failure::Error;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::rand::Rng;
+
+use super::commands::ControllerClient;
+use super::{Auth, NetworkId, NodeAddress};
+
+const API_VERSION: u64 = 3;
+const VERSION: &str = "1.2.12";
+
+#[derive(Default)]
+struct State {
+    networks: BTreeMap<String, Value>,
+    members: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+/// A running mock controller, it stops when dropped
+pub struct MockController {
+    address: NodeAddress,
+    token: String,
+    base_url: String,
+    state: Arc<Mutex<State>>,
+    server: Arc<tiny_http::Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockController {
+    /// Start a controller with node address `address` (10 hex digits) that
+    /// wants `token`, on a free port of 127.0.0.1
+    pub fn start(address: &str, token: &str) -> Result<Self, Error> {
+        let address: NodeAddress = address.parse()?;
+        let server = tiny_http::Server::http("127.0.0.1:0").map_err(Error::from_boxed_compat)?;
+        let server = Arc::new(server);
+        let port = match server.server_addr().to_ip() {
+            Some(a) => a.port(),
+            None => return Err(failure::err_msg("mock controller has no ip address")),
+        };
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let (srv, st, addr, tok) = (
+            server.clone(),
+            state.clone(),
+            address.to_string(),
+            token.to_owned(),
+        );
+        let thread = std::thread::spawn(move || {
+            for mut req in srv.incoming_requests() {
+                let (code, body) = handle(&mut req, &st, &addr, &tok);
+                let resp = tiny_http::Response::from_string(body.to_string())
+                    .with_status_code(code)
+                    .with_header(
+                        tiny_http::Header::from_bytes(
+                            &b"Content-Type"[..],
+                            &b"application/json"[..],
+                        )
+                        .unwrap(),
+                    );
+                let _ = req.respond(resp);
+            }
+        });
+
+        Ok(MockController {
+            address,
+            token: token.to_owned(),
+            base_url: format!("http://127.0.0.1:{}", port),
+            state,
+            server,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// What a client on the controller host would read from disk
+    pub fn auth(&self) -> Auth {
+        Auth {
+            serverid: Some(self.address),
+            auth_token: self.token.clone(),
+        }
+    }
+
+    /// A client that talks to this controller
+    pub fn client(&self) -> Result<ControllerClient, Error> {
+        ControllerClient::with(&self.base_url, self.auth(), None, None)
+    }
+
+    /// Make node `id` ask to join network `nwid`, like a `zerotier-cli join`
+    /// would. It shows up as an unauthorized member.
+    pub fn join(&self, nwid: NetworkId, id: NodeAddress) {
+        let (nwid, id) = (nwid.to_string(), id.to_string());
+        let mut st = self.state.lock().unwrap();
+        if st.networks.contains_key(&nwid) {
+            let members = st.members.entry(nwid.clone()).or_default();
+            members
+                .entry(id.clone())
+                .or_insert_with(|| new_member(&nwid, &id));
+        }
+    }
+}
+
+impl Drop for MockController {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn not_found() -> (u16, Value) {
+    (404, json!({}))
+}
+
+fn handle(
+    req: &mut tiny_http::Request,
+    st: &Mutex<State>,
+    address: &str,
+    token: &str,
+) -> (u16, Value) {
+    let url = req.url().to_owned();
+    let mut parts = url.splitn(2, '?');
+    let path = parts.next().unwrap_or("").to_owned();
+    let query = parts.next().unwrap_or("");
+
+    let header_token = req
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("X-ZT1-Auth"))
+        .map(|h| h.value.as_str().to_owned());
+    let query_token = query
+        .split('&')
+        .find(|kv| kv.starts_with("auth="))
+        .map(|kv| kv[5..].to_owned());
+    if header_token.as_deref() != Some(token) && query_token.as_deref() != Some(token) {
+        return (401, json!({}));
+    }
+
+    let method = req.method().as_str().to_uppercase();
+    let body: Option<Value> = if method == "POST" {
+        let mut s = String::new();
+        if req.as_reader().read_to_string(&mut s).is_err() {
+            return (400, json!({}));
+        }
+        match serde_json::from_str(&s) {
+            Ok(v @ Value::Object(_)) => Some(v),
+            _ => return (400, json!({})),
+        }
+    } else {
+        None
+    };
+
+    let mut st = st.lock().unwrap();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method.as_str(), &segments[..]) {
+        ("GET", ["status"]) => (
+            200,
+            json!({ "address": address, "online": true, "version": VERSION, "clock": now() }),
+        ),
+        ("GET", ["controller"]) => (
+            200,
+            json!({ "controller": true, "apiVersion": API_VERSION, "clock": now() }),
+        ),
+        ("GET", ["controller", "network"]) => (200, json!(st.networks.keys().collect::<Vec<_>>())),
+        ("GET", ["controller", "network", nwid]) => match st.networks.get(*nwid) {
+            Some(n) => (200, n.clone()),
+            None => not_found(),
+        },
+        ("POST", ["controller", "network", nwid]) => {
+            let nwid = if nwid.len() == 16 && nwid.ends_with("______") {
+                if &nwid[..10] != address {
+                    return (400, json!({}));
+                }
+                let mut rng = rand::thread_rng();
+                loop {
+                    let n = format!("{}{:06x}", address, rng.gen::<u32>() & 0xff_ffff);
+                    if !st.networks.contains_key(&n) {
+                        break n;
+                    }
+                }
+            } else {
+                nwid.to_string()
+            };
+            let net = st
+                .networks
+                .entry(nwid.clone())
+                .or_insert_with(|| new_network(&nwid));
+            merge(net, body.unwrap());
+            (200, net.clone())
+        }
+        ("DELETE", ["controller", "network", nwid]) => {
+            st.members.remove(*nwid);
+            match st.networks.remove(*nwid) {
+                Some(n) => (200, n),
+                None => not_found(),
+            }
+        }
+        ("GET", ["controller", "network", nwid, "member"]) => {
+            if !st.networks.contains_key(*nwid) {
+                return not_found();
+            }
+            let mut revisions = Map::new();
+            if let Some(members) = st.members.get(*nwid) {
+                for (id, m) in members {
+                    revisions.insert(id.clone(), m["revision"].clone());
+                }
+            }
+            (200, Value::Object(revisions))
+        }
+        ("GET", ["controller", "network", nwid, "member", id]) => {
+            match st.members.get(*nwid).and_then(|m| m.get(*id)) {
+                Some(m) => (200, m.clone()),
+                None => not_found(),
+            }
+        }
+        ("POST", ["controller", "network", nwid, "member", id]) => {
+            if !st.networks.contains_key(*nwid) {
+                return not_found();
+            }
+            let members = st.members.entry(nwid.to_string()).or_default();
+            let member = members
+                .entry(id.to_string())
+                .or_insert_with(|| new_member(nwid, id));
+            merge(member, body.unwrap());
+            (200, member.clone())
+        }
+        ("DELETE", ["controller", "network", nwid, "member", id]) => {
+            match st.members.get_mut(*nwid).and_then(|m| m.remove(*id)) {
+                Some(m) => (200, m),
+                None => not_found(),
+            }
+        }
+        _ => not_found(),
+    }
+}
+
+/// What the controller makes of a network nobody configured yet
+fn new_network(nwid: &str) -> Value {
+    json!({
+        "id": nwid,
+        "nwid": nwid,
+        "objtype": "network",
+        "name": "",
+        "private": true,
+        "creationTime": now(),
+        "revision": 0,
+        "enableBroadcast": true,
+        "multicastLimit": 32,
+        "mtu": 2800,
+        "dns": { "domain": "", "servers": [] },
+        "v4AssignMode": { "zt": false },
+        "v6AssignMode": { "zt": false, "rfc4193": false, "6plane": false },
+        "routes": [],
+        "ipAssignmentPools": [],
+        "rules": [ { "not": false, "or": false, "type": "ACTION_ACCEPT" } ],
+        "capabilities": [],
+        "tags": []
+    })
+}
+
+fn new_member(nwid: &str, id: &str) -> Value {
+    json!({
+        "id": id,
+        "address": id,
+        "nwid": nwid,
+        "objtype": "member",
+        "authorized": false,
+        "activeBridge": false,
+        "ipAssignments": [],
+        "tags": [],
+        "capabilities": [],
+        "noAutoAssignIps": false,
+        "creationTime": now(),
+        "revision": 0
+    })
+}
+
+/// Take over what was posted, except what the controller owns. Assign modes
+/// sent as strings come back as objects, just like on the daemon.
+fn merge(into: &mut Value, posted: Value) {
+    let posted = match posted {
+        Value::Object(o) => o,
+        _ => return,
+    };
+    let obj = into.as_object_mut().unwrap();
+    for (k, v) in posted {
+        match k.as_str() {
+            "id" | "nwid" | "address" | "objtype" | "revision" | "creationTime" => {}
+            "v4AssignMode" | "v6AssignMode" => {
+                let v = match v {
+                    Value::String(s) => {
+                        let modes: Vec<&str> = s.split(',').collect();
+                        let mut m = Map::new();
+                        let flags: &[&str] = if k == "v4AssignMode" {
+                            &["zt"]
+                        } else {
+                            &["zt", "rfc4193", "6plane"]
+                        };
+                        for f in flags {
+                            m.insert(f.to_string(), Value::Bool(modes.contains(f)));
+                        }
+                        Value::Object(m)
+                    }
+                    v => v,
+                };
+                obj.insert(k, v);
+            }
+            _ => {
+                obj.insert(k, v);
+            }
+        }
+    }
+    let revision = obj.get("revision").and_then(|r| r.as_u64()).unwrap_or(0);
+    obj.insert("revision".to_owned(), json!(revision + 1));
+}
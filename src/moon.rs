@@ -0,0 +1,366 @@
+//! Moons are zerotier's custom roots: a world definition that lists a set of
+//! root nodes with stable endpoints, signed with a key that has to sign all
+//! later updates of it too. Clients that `zerotier-cli orbit <id> <seed>`
+//! fetch it from the seed node.
+//!
+//! We follow `zerotier-idtool`: `MoonDefinition::init` is `initmoon`, and
+//! produces the same JSON, `MoonDefinition::world` is `genmoon` and produces
+//! the signed binary that goes into `moons.d`.
+
+extern crate ed25519_dalek;
+extern crate hex;
+extern crate rand;
+extern crate sha2;
+extern crate x25519_dalek;
+
+use failure::Error;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::ed25519_dalek::Signer;
+use self::rand::RngCore;
+use self::sha2::{Digest, Sha512};
+
+use super::{Identity, NodeAddress, ZTError};
+
+const WORLD_TYPE_MOON: u8 = 127;
+
+/// A root of the moon, its identity in `address:0:public` form and the
+/// `ip/port` endpoints it can always be reached on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MoonRoot {
+    pub identity: String,
+    #[serde(rename = "stableEndpoints")]
+    pub stable_endpoints: Vec<String>,
+}
+
+/// The moon definition as `zerotier-idtool initmoon` writes it. It holds
+/// the secret signing key, so keep it somewhere safe: without it the moon
+/// can't be updated anymore.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MoonDefinition {
+    pub id: String,
+    pub objtype: String,
+    pub roots: Vec<MoonRoot>,
+    #[serde(rename = "signingKey")]
+    pub signing_key: String,
+    #[serde(rename = "signingKey_SECRET")]
+    pub signing_key_secret: String,
+    #[serde(rename = "updatesMustBeSignedBy")]
+    pub updates_must_be_signed_by: String,
+    #[serde(rename = "worldType")]
+    pub world_type: String,
+}
+
+impl MoonDefinition {
+    /// Start a moon with the node of `identity` (the content of its
+    /// `identity.public`) as its only root, and a fresh signing key.
+    /// The moon id is the address of that node.
+    pub fn init(identity: &str) -> Result<Self, Error> {
+        let identity: Identity = identity.parse()?;
+        let (public, secret) = generate_keypair();
+        Ok(MoonDefinition {
+            id: identity.address().to_string(),
+            objtype: "world".to_owned(),
+            roots: vec![MoonRoot {
+                // only ever the public part, even when handed identity.secret
+                identity: identity.to_string(),
+                stable_endpoints: vec![],
+            }],
+            signing_key: hex::encode(&public[..]),
+            signing_key_secret: hex::encode(&secret[..]),
+            updates_must_be_signed_by: hex::encode(&public[..]),
+            world_type: "moon".to_owned(),
+        })
+    }
+
+    /// Read a definition written earlier, e.g. by `zerotier-idtool initmoon`
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let j = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&j)?)
+    }
+
+    /// Keep the definition, the file only gets read and write for its owner
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut f = create_private(path)?;
+        f.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Add a stable endpoint to the root with that address
+    pub fn add_stable_endpoint(&mut self, address: NodeAddress, ep: SocketAddr) -> Result<(), Error> {
+        let ep = format!("{}/{}", ep.ip(), ep.port());
+        for r in &mut self.roots {
+            if r.identity.parse::<Identity>()?.address() == address {
+                if !r.stable_endpoints.contains(&ep) {
+                    r.stable_endpoints.push(ep);
+                }
+                return Ok(());
+            }
+        }
+        Err(ZTError {
+            code: 121i32,
+            message: format!("moon {} has no root {}", self.id, address),
+        }
+        .into())
+    }
+
+    /// What clients `orbit` with: the moon id, and the node to fetch it from
+    pub fn seed(&self) -> &str {
+        &self.id
+    }
+
+    /// The name `genmoon` gives the world file, the moon id in 16 hex digits
+    pub fn file_name(&self) -> Result<String, Error> {
+        Ok(format!("{:016x}.moon", self.world_id()?))
+    }
+
+    /// The signed binary world, timestamped at `ts` milliseconds since the
+    /// epoch. Clients take an update only when it's newer than what they have.
+    pub fn world(&self, ts: u64) -> Result<Vec<u8>, Error> {
+        let id = self.world_id()?;
+        let signed_by = decode_key(&self.updates_must_be_signed_by, "updatesMustBeSignedBy")?;
+        let secret = decode_key(&self.signing_key_secret, "signingKey_SECRET")?;
+        let mut roots: Vec<u8> = Vec::new();
+        for r in &self.roots {
+            let identity: Identity = r.identity.parse()?;
+            if r.stable_endpoints.is_empty() {
+                return Err(ZTError {
+                    code: 122i32,
+                    message: format!("root {} has no stable endpoints", identity.address()),
+                }
+                .into());
+            }
+            roots.extend_from_slice(&identity.address().as_u64().to_be_bytes()[3..]);
+            roots.push(0);
+            roots.extend_from_slice(identity.public_key());
+            // no private key in here
+            roots.push(0);
+            roots.push(count(r.stable_endpoints.len(), "stable endpoints")?);
+            for ep in &r.stable_endpoints {
+                serialize_endpoint(&mut roots, &parse_endpoint(ep)?);
+            }
+        }
+
+        let mut head: Vec<u8> = vec![WORLD_TYPE_MOON];
+        head.extend_from_slice(&id.to_be_bytes());
+        head.extend_from_slice(&ts.to_be_bytes());
+        head.extend_from_slice(&signed_by);
+        let mut tail: Vec<u8> = vec![count(self.roots.len(), "roots")?];
+        tail.extend_from_slice(&roots);
+        // no attached dictionary
+        tail.extend_from_slice(&0u16.to_be_bytes());
+
+        // what gets signed is framed by markers and has no signature
+        let mut to_sign: Vec<u8> = 0x7f7f_7f7f_7f7f_7f7fu64.to_be_bytes().to_vec();
+        to_sign.extend_from_slice(&head);
+        to_sign.extend_from_slice(&tail);
+        to_sign.extend_from_slice(&0xf7f7_f7f7_f7f7_f7f7u64.to_be_bytes());
+
+        let mut world = head;
+        world.extend_from_slice(&sign(&secret, &to_sign));
+        world.extend_from_slice(&tail);
+        Ok(world)
+    }
+
+    /// Write the signed world into `<home>/moons.d`, where the daemon picks
+    /// it up. Returns the path of the world file.
+    pub fn deploy(&self, home: &Path, ts: u64) -> Result<PathBuf, Error> {
+        let dir = home.join("moons.d");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(self.file_name()?);
+        std::fs::write(&path, self.world(ts)?)?;
+        Ok(path)
+    }
+
+    fn world_id(&self) -> Result<u64, Error> {
+        match u64::from_str_radix(&self.id, 16) {
+            Ok(id) => Ok(id),
+            Err(_) => Err(ZTError {
+                code: 123i32,
+                message: format!("invalid moon id {}", self.id),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Make the local node (the one living in `home`) root of its moon, on the
+/// given stable endpoints. The definition is kept in `<home>/moon.json` and
+/// reused next time, so the moon keeps its id and signing key, and the
+/// signed world goes into `<home>/moons.d` stamped with the current time.
+pub fn deploy_local(home: &Path, endpoints: &[SocketAddr]) -> Result<MoonDefinition, Error> {
+    let definition = home.join("moon.json");
+    let mut moon = if definition.exists() {
+        MoonDefinition::load(&definition)?
+    } else {
+        let identity = std::fs::read_to_string(home.join("identity.public"))?;
+        MoonDefinition::init(&identity)?
+    };
+    let seed: NodeAddress = moon.seed().parse()?;
+    for ep in endpoints {
+        moon.add_stable_endpoint(seed, *ep)?;
+    }
+    moon.save(&definition)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    moon.deploy(home, now.as_millis() as u64)?;
+    Ok(moon)
+}
+
+/// A C25519 key pair the way zerotier has it: the curve25519 key for
+/// agreement in the first half, the ed25519 key for signing in the second.
+fn generate_keypair() -> ([u8; 64], [u8; 64]) {
+    let mut secret = [0u8; 64];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let mut dh_secret = [0u8; 32];
+    dh_secret.copy_from_slice(&secret[..32]);
+    let mut ed_secret = [0u8; 32];
+    ed_secret.copy_from_slice(&secret[32..]);
+
+    let mut public = [0u8; 64];
+    let dh = x25519_dalek::StaticSecret::from(dh_secret);
+    public[..32].copy_from_slice(x25519_dalek::PublicKey::from(&dh).as_bytes());
+    let ed = ed25519_dalek::SigningKey::from_bytes(&ed_secret);
+    public[32..].copy_from_slice(ed.verifying_key().as_bytes());
+    (public, secret)
+}
+
+/// Zerotier signs the first 32 bytes of the SHA-512 of a message with
+/// ed25519, and tacks those 32 bytes onto the signature.
+fn sign(secret: &[u8; 64], msg: &[u8]) -> Vec<u8> {
+    let digest = Sha512::digest(msg);
+    let mut ed_secret = [0u8; 32];
+    ed_secret.copy_from_slice(&secret[32..]);
+    let ed = ed25519_dalek::SigningKey::from_bytes(&ed_secret);
+    let mut sig = ed.sign(&digest[..32]).to_bytes().to_vec();
+    sig.extend_from_slice(&digest[..32]);
+    sig
+}
+
+fn decode_key(h: &str, what: &str) -> Result<[u8; 64], Error> {
+    let mut key = [0u8; 64];
+    match hex::decode(h) {
+        Ok(ref k) if k.len() == 64 => key.copy_from_slice(k),
+        _ => {
+            return Err(ZTError {
+                code: 124i32,
+                message: format!("{} is not a 64 byte hex key", what),
+            }
+            .into())
+        }
+    }
+    Ok(key)
+}
+
+/// Endpoints are written `ip/port` in zerotier land
+pub fn parse_endpoint(ep: &str) -> Result<SocketAddr, Error> {
+    let mut parts = ep.splitn(2, '/');
+    let ip = parts.next().unwrap_or("").parse::<IpAddr>();
+    let port = parts.next().unwrap_or("").parse::<u16>();
+    match (ip, port) {
+        (Ok(ip), Ok(port)) => Ok(SocketAddr::new(ip, port)),
+        _ => Err(ZTError {
+            code: 126i32,
+            message: format!("invalid stable endpoint {}", ep),
+        }
+        .into()),
+    }
+}
+
+fn serialize_endpoint(b: &mut Vec<u8>, ep: &SocketAddr) {
+    match ep.ip() {
+        IpAddr::V4(v4) => {
+            b.push(0x04);
+            b.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            b.push(0x06);
+            b.extend_from_slice(&v6.octets());
+        }
+    }
+    b.extend_from_slice(&ep.port().to_be_bytes());
+}
+
+/// The world keeps its counts in a single byte
+fn count(n: usize, what: &str) -> Result<u8, Error> {
+    if n > u8::MAX as usize {
+        return Err(ZTError {
+            code: 125i32,
+            message: format!("a moon can hold at most {} {}, not {}", u8::MAX, what, n),
+        }
+        .into());
+    }
+    Ok(n as u8)
+}
+
+/// Truncate or create `path`, readable and writable only by its owner before
+/// anything is in it. A file that was already there gets narrowed as well.
+#[cfg(unix)]
+fn create_private(path: &Path) -> Result<std::fs::File, Error> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    f.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    Ok(f)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> Result<std::fs::File, Error> {
+    Ok(std::fs::File::create(path)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use self::ed25519_dalek::Verifier;
+
+    const IDENTITY: &str = "8056c2e21c:0:3b2caa9d4e6bbe4d0f36dc1d1c4a7c9f6e0c7dd2bae2e1d7e26b4f6e9a1d7b8c62f3a8f6d93c9bde1e5f9b4c7a2d8e3f1a6b9c0d7e2f4a5b8c1d3e6f9a0b2c4d";
+
+    #[test]
+    fn test_moon_world() -> Result<(), Error> {
+        let mut moon = MoonDefinition::init(IDENTITY)?;
+        assert_eq!(moon.seed(), "8056c2e21c");
+        assert_eq!(moon.file_name()?, "0000008056c2e21c.moon");
+        assert!(moon.world(1).is_err());
+        moon.add_stable_endpoint("8056c2e21c".parse()?, "195.1.2.3:9993".parse()?)?;
+        assert_eq!(moon.roots[0].stable_endpoints, vec!["195.1.2.3/9993"]);
+
+        let world = moon.world(1_546_300_800_000)?;
+        // type, id, timestamp, signer, signature, 1 root, address, key type,
+        // public key, private key length, 1 endpoint of 7 bytes, dictionary
+        assert_eq!(world.len(), 1 + 8 + 8 + 64 + 96 + 1 + 5 + 1 + 64 + 1 + 1 + 7 + 2);
+        assert_eq!(world[0], WORLD_TYPE_MOON);
+        assert_eq!(&world[1..9], &0x8056c2e21cu64.to_be_bytes());
+
+        // the signature has to check out against the ed25519 half of the key
+        let signer = decode_key(&moon.updates_must_be_signed_by, "")?;
+        let mut ed_public = [0u8; 32];
+        ed_public.copy_from_slice(&signer[32..]);
+        let mut to_sign: Vec<u8> = 0x7f7f_7f7f_7f7f_7f7fu64.to_be_bytes().to_vec();
+        to_sign.extend_from_slice(&world[..81]);
+        to_sign.extend_from_slice(&world[177..]);
+        to_sign.extend_from_slice(&0xf7f7_f7f7_f7f7_f7f7u64.to_be_bytes());
+        let digest = Sha512::digest(&to_sign);
+        assert_eq!(&world[145..177], &digest[..32]);
+        let mut sig = [0u8; 64];
+        sig.copy_from_slice(&world[81..145]);
+        ed25519_dalek::VerifyingKey::from_bytes(&ed_public)?
+            .verify(&digest[..32], &ed25519_dalek::Signature::from_bytes(&sig))?;
+
+        // counts are a single byte, more than that can't be framed
+        for i in 0..255 {
+            moon.roots[0].stable_endpoints.push(format!("10.0.{}.{}/9993", i / 256, i % 256));
+        }
+        match moon.world(1) {
+            Err(e) => assert_eq!(e.downcast_ref::<ZTError>().unwrap().code(), 125),
+            Ok(_) => panic!("256 endpoints fit in the world"),
+        }
+        Ok(())
+    }
+}
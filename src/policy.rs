@@ -0,0 +1,147 @@
+//! Ready-made policies for the usual kinds of network, in the rules
+//! language. Each fills in the rules, capabilities and tags of a network
+//! together:
+//!
+//! ```text
+//!     ip-only            IPv4, ARP and IPv6, nothing else
+//!     ipv6-only          IPv6 and nothing else
+//!     no-inter-member    members only talk to nodes tagged gateway 1
+//!     web-only           http(s), plus what IP needs to work (ARP, ICMP)
+//!     admin-capability   ssh only for members granted the admin capability
+//! ```
+
+use super::rules::{compile, CompiledRules};
+use super::ZTError;
+
+/// The names of all policies
+pub const NAMES: [&str; 5] = ["ip-only", "ipv6-only", "no-inter-member", "web-only", "admin-capability"];
+
+const IP_ONLY: &str = "
+drop not ethertype ipv4 and not ethertype arp and not ethertype ipv6;
+accept;
+";
+
+const IPV6_ONLY: &str = "
+drop not ethertype ipv6;
+accept;
+";
+
+/// The gateway tag is 0 unless set, so members without it only reach
+/// the gateways
+const NO_INTER_MEMBER: &str = "
+tag gateway id 100 enum 0 no enum 1 yes default no;
+drop not ethertype ipv4 and not ethertype arp and not ethertype ipv6;
+accept tor gateway yes;
+drop;
+";
+
+/// Both directions, the rules don't keep connection state. Only the
+/// replies of web servers come from their ports, so a connection opened
+/// (SYN without ACK) from port 80 or 443 to anything else is dropped.
+const WEB_ONLY: &str = "
+drop not ethertype ipv4 and not ethertype arp and not ethertype ipv6;
+drop ipprotocol tcp and chr tcp_syn and not chr tcp_ack and not dport 80 and not dport 443;
+accept ethertype arp;
+accept ipprotocol icmp or ipprotocol icmp6;
+accept ipprotocol tcp and dport 80;
+accept ipprotocol tcp and dport 443;
+accept ipprotocol tcp and sport 80;
+accept ipprotocol tcp and sport 443;
+drop;
+";
+
+/// New ssh connections break out of the rules, to be accepted by the
+/// capability or dropped; the replies of the server are accepted as usual
+const ADMIN_CAPABILITY: &str = "
+drop not ethertype ipv4 and not ethertype arp and not ethertype ipv6;
+cap admin
+  id 1000
+  accept ipprotocol tcp and dport 22;
+;
+break ipprotocol tcp and dport 22;
+accept;
+";
+
+/// The rules source of a policy
+pub fn source(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "ip-only" => IP_ONLY,
+        "ipv6-only" => IPV6_ONLY,
+        "no-inter-member" => NO_INTER_MEMBER,
+        "web-only" => WEB_ONLY,
+        "admin-capability" => ADMIN_CAPABILITY,
+        _ => return None,
+    })
+}
+
+/// A policy, compiled and ready to `apply_to` a network
+pub fn policy(name: &str) -> Result<CompiledRules, ZTError> {
+    match source(name) {
+        Some(s) => Ok(compile(s)?),
+        None => Err(ZTError {
+            code: 171i32,
+            message: format!("no policy {}, there are {}", name, NAMES.join(", ")),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::simulate::{evaluate, Frame, Verdict};
+    use super::super::{RootInterface, ZT_ETHERTYPE_ARP, ZT_ETHERTYPE_IPV4};
+    use super::*;
+
+    fn network(name: &str) -> RootInterface {
+        let mut net = RootInterface::default();
+        policy(name).unwrap().apply_to(&mut net);
+        net
+    }
+
+    fn tcp(dport: u16) -> Frame {
+        Frame {
+            ether_type: ZT_ETHERTYPE_IPV4,
+            ip_protocol: Some(6),
+            source_port: Some(40000),
+            dest_port: Some(dport),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_policies() {
+        for name in NAMES.iter() {
+            assert!(!network(name).rules.is_empty());
+        }
+        assert_eq!(policy("open").unwrap_err().code(), 171);
+
+        let arp = Frame { ether_type: ZT_ETHERTYPE_ARP, ..Default::default() };
+        assert_eq!(evaluate(&network("ip-only"), &arp).verdict, Verdict::Accept);
+        assert_eq!(evaluate(&network("ipv6-only"), &arp).verdict, Verdict::Drop);
+
+        let net = network("web-only");
+        assert_eq!(evaluate(&net, &tcp(443)).verdict, Verdict::Accept);
+        assert_eq!(evaluate(&net, &tcp(22)).verdict, Verdict::Drop);
+        let mut syn = tcp(22);
+        syn.source_port = Some(443);
+        syn.characteristics = 1 << 1;
+        assert_eq!(evaluate(&net, &syn).verdict, Verdict::Drop);
+        let mut syn_ack = tcp(40000);
+        syn_ack.source_port = Some(443);
+        syn_ack.characteristics = 1 << 1 | 1 << 4;
+        assert_eq!(evaluate(&net, &syn_ack).verdict, Verdict::Accept);
+
+        let net = network("no-inter-member");
+        assert_eq!(evaluate(&net, &tcp(22)).verdict, Verdict::Drop);
+        let mut to_gateway = tcp(22);
+        to_gateway.receiver_tags.insert(100, 1);
+        assert_eq!(evaluate(&net, &to_gateway).verdict, Verdict::Accept);
+
+        let net = network("admin-capability");
+        assert_eq!(net.capabilities[0].id, 1000);
+        assert_eq!(evaluate(&net, &tcp(22)).verdict, Verdict::Drop);
+        assert_eq!(evaluate(&net, &tcp(80)).verdict, Verdict::Accept);
+        let mut admin = tcp(22);
+        admin.sender_capabilities.push(1000);
+        assert_eq!(evaluate(&net, &admin).verdict, Verdict::Accept);
+    }
+}
@@ -0,0 +1,1057 @@
+//! A compiler for zerotier's flow rules language, the one ZeroTier Central
+//! takes, down to the `rules`, `capabilities` and `tags` JSON the controller
+//! wants.
+//!
+//! ```text
+//!     # only IP and ARP, and no ssh from outside of the admins
+//!     drop not ethertype ipv4 and not ethertype arp and not ethertype ipv6;
+//!
+//!     tag role id 1000 enum 10 admin enum 20 user default 20;
+//!
+//!     macro allow_port($port)
+//!       accept ipprotocol tcp and dport $port;
+//!     ;
+//!
+//!     cap ssh
+//!       id 2000
+//!       include allow_port(22);
+//!     ;
+//!
+//!     break chr tcp_syn and not chr tcp_ack and dport 22 and teq role user;
+//!     accept;
+//! ```
+//!
+//! A rule is an action (`drop`, `accept`, `break`, `debug`, `tee <length> <address>`,
+//! `watch <length> <address>`, `redirect <address>`, a length of `-1` sends
+//! the whole packet and `flags <n>` may follow the address) followed by matches,
+//! that can be joined with `and` (the default) or `or`, and negated with
+//! `not`; it ends with `;`. `cap` and `macro` blocks end with an empty
+//! statement, a lone `;`. Macros are expanded where `include` names them,
+//! their `$parameters` replaced by the arguments. `#` comments to the end
+//! of the line.
+//!
+//! `decompile` goes the other way, from a network's JSON back to source
+//! that compiles to the same rules.
+
+use ipnet::IpNet;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::{Capability, NodeAddress, RootInterface, TagDefinition, Rule, Rules, ZTError, ZT_ETHERTYPE_ARP, ZT_ETHERTYPE_IPV4, ZT_ETHERTYPE_IPV6};
+
+/// Includes nest at most this deep, so a macro can't include itself forever
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// What went wrong where, lines and columns start at 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl From<RuleError> for ZTError {
+    fn from(e: RuleError) -> Self {
+        ZTError {
+            code: 151i32,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// The names a tag definition gave to its id, values and bits, as ZeroTier
+/// Central keeps them in `tagsByName`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NamedTag {
+    pub id: u32,
+    pub default: Option<u32>,
+    pub enums: BTreeMap<String, u32>,
+    pub flags: BTreeMap<String, u32>,
+}
+
+/// The outcome of a rules source, ready to be set on a network
+#[derive(Debug, Default)]
+pub struct CompiledRules {
+    pub rules: Vec<Rules>,
+    pub capabilities: Vec<Capability>,
+    pub tags: Vec<TagDefinition>,
+    pub capabilities_by_name: BTreeMap<String, u32>,
+    pub tags_by_name: BTreeMap<String, NamedTag>,
+}
+
+impl CompiledRules {
+    /// Replace the rules, capabilities and tags of `net` with ours
+    pub fn apply_to(self, net: &mut RootInterface) {
+        net.rules = self.rules;
+        net.capabilities = self.capabilities;
+        net.tags = self.tags;
+    }
+}
+
+/// Compile a rules source
+pub fn compile(source: &str) -> Result<CompiledRules, RuleError> {
+    let tokens = lex(source);
+    let mut macros = BTreeMap::new();
+    let tokens = expand(&tokens, &mut macros, 0)?;
+    let mut p = Parser {
+        tokens,
+        pos: 0,
+        out: CompiledRules::default(),
+    };
+    p.program()?;
+    Ok(p.out)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Token {
+    pub(crate) text: String,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+impl Token {
+    pub(crate) fn is(&self, text: &str) -> bool {
+        self.text == text
+    }
+}
+
+pub(crate) fn error(t: &Token, message: String) -> RuleError {
+    RuleError {
+        line: t.line,
+        column: t.column,
+        message,
+    }
+}
+
+/// Words are whatever sits between whitespace and `;(),`, which are tokens
+/// of their own
+pub(crate) fn lex(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (l, line) in source.lines().enumerate() {
+        let mut word: Option<Token> = None;
+        for (c, ch) in line.chars().enumerate() {
+            if ch == '#' || ch.is_whitespace() || ";(),".contains(ch) {
+                if let Some(w) = word.take() {
+                    tokens.push(w);
+                }
+                if ch == '#' {
+                    break;
+                }
+                if !ch.is_whitespace() {
+                    tokens.push(Token {
+                        text: ch.to_string(),
+                        line: l + 1,
+                        column: c + 1,
+                    });
+                }
+                continue;
+            }
+            word.get_or_insert_with(|| Token {
+                text: String::new(),
+                line: l + 1,
+                column: c + 1,
+            })
+            .text
+            .push(ch);
+        }
+        if let Some(w) = word.take() {
+            tokens.push(w);
+        }
+    }
+    tokens
+}
+
+/// The index of the lone `;` that closes the block whose body starts at
+/// `start`
+fn block_end(tokens: &[Token], start: usize) -> Option<usize> {
+    (start..tokens.len()).find(|&i| tokens[i].is(";") && (i == start || tokens[i - 1].is(";")))
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Take out the macro definitions, and put their bodies where they're
+/// included
+fn expand(
+    tokens: &[Token],
+    macros: &mut BTreeMap<String, Macro>,
+    depth: usize,
+) -> Result<Vec<Token>, RuleError> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let t = &tokens[i];
+        if t.is("macro") || t.is("include") {
+            let (name, args, next) = call(tokens, i)?;
+            if t.is("macro") {
+                let end = match block_end(tokens, next) {
+                    Some(e) => e,
+                    None => return Err(error(t, format!("macro {} is not closed with ;", name))),
+                };
+                for a in &args {
+                    if !a.text.starts_with('$') {
+                        return Err(error(a, format!("macro parameter {} must start with $", a.text)));
+                    }
+                }
+                macros.insert(
+                    name,
+                    Macro {
+                        params: args.into_iter().map(|a| a.text).collect(),
+                        body: tokens[next..end].to_vec(),
+                    },
+                );
+                i = end + 1;
+            } else {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(error(t, format!("includes of {} nest too deep", name)));
+                }
+                let m = match macros.get(&name) {
+                    Some(m) => m,
+                    None => return Err(error(t, format!("no macro {}", name))),
+                };
+                if m.params.len() != args.len() {
+                    return Err(error(
+                        t,
+                        format!("macro {} takes {} arguments, got {}", name, m.params.len(), args.len()),
+                    ));
+                }
+                let body: Vec<Token> = m
+                    .body
+                    .iter()
+                    .map(|b| match m.params.iter().position(|p| *p == b.text) {
+                        Some(n) => args[n].clone(),
+                        None => b.clone(),
+                    })
+                    .collect();
+                out.extend(expand(&body, macros, depth + 1)?);
+                match tokens.get(next) {
+                    Some(s) if s.is(";") => i = next + 1,
+                    _ => return Err(error(t, format!("missing ; after include {}", name))),
+                }
+            }
+        } else {
+            out.push(t.clone());
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+/// `macro name($a, $b)` or `include name(x, y)` at `i`: the name, the
+/// arguments, and where it continues
+fn call(tokens: &[Token], i: usize) -> Result<(String, Vec<Token>, usize), RuleError> {
+    let kw = &tokens[i];
+    let name = match tokens.get(i + 1) {
+        Some(n) if is_word(n) => n.text.clone(),
+        _ => return Err(error(kw, format!("{} needs a name", kw.text))),
+    };
+    let mut args = Vec::new();
+    let mut j = i + 2;
+    match tokens.get(j) {
+        Some(t) if t.is("(") => j += 1,
+        _ => return Ok((name, args, j)),
+    }
+    loop {
+        match tokens.get(j) {
+            Some(t) if t.is(")") && args.is_empty() => return Ok((name, args, j + 1)),
+            Some(t) if is_word(t) => args.push(t.clone()),
+            _ => return Err(error(kw, format!("bad argument list for {}", name))),
+        }
+        match tokens.get(j + 1) {
+            Some(t) if t.is(",") => j += 2,
+            Some(t) if t.is(")") => return Ok((name, args, j + 2)),
+            _ => return Err(error(kw, format!("bad argument list for {}", name))),
+        }
+    }
+}
+
+fn is_word(t: &Token) -> bool {
+    !";(),".contains(t.text.as_str())
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    out: CompiledRules,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// The next token, or an error just past the last one
+    fn next(&mut self, wanted: &str) -> Result<Token, RuleError> {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.pos += 1;
+                Ok(t.clone())
+            }
+            None => {
+                let (line, column) = match self.tokens.last() {
+                    Some(t) => (t.line, t.column + t.text.chars().count()),
+                    None => (1, 1),
+                };
+                Err(RuleError {
+                    line,
+                    column,
+                    message: format!("expected {}, got the end of the rules", wanted),
+                })
+            }
+        }
+    }
+
+    fn word(&mut self, wanted: &str) -> Result<Token, RuleError> {
+        let t = self.next(wanted)?;
+        if !is_word(&t) {
+            return Err(error(&t, format!("expected {}, got {}", wanted, t.text)));
+        }
+        Ok(t)
+    }
+
+    fn number(&mut self, wanted: &str, max: u64) -> Result<u64, RuleError> {
+        let t = self.word(wanted)?;
+        parse_number(&t, wanted, max)
+    }
+
+    fn program(&mut self) -> Result<(), RuleError> {
+        while let Some(t) = self.peek() {
+            if t.is(";") {
+                self.pos += 1;
+            } else if t.is("cap") {
+                self.capability()?;
+            } else if t.is("tag") {
+                self.tag()?;
+            } else {
+                let mut rules = std::mem::take(&mut self.out.rules);
+                self.rule(&mut rules)?;
+                self.out.rules = rules;
+            }
+        }
+        Ok(())
+    }
+
+    /// `cap <name> id <id> <rules> ;`
+    fn capability(&mut self) -> Result<(), RuleError> {
+        let kw = self.next("cap")?;
+        let name = self.word("a capability name")?;
+        let t = self.word("id")?;
+        if !t.is("id") {
+            return Err(error(&t, format!("expected id, got {}", t.text)));
+        }
+        let id = self.number("a capability id", u64::from(u32::MAX))? as u32;
+        if self.out.capabilities_by_name.contains_key(&name.text)
+            || self.out.capabilities_by_name.values().any(|&i| i == id)
+        {
+            return Err(error(&name, format!("capability {} (id {}) defined twice", name.text, id)));
+        }
+        let mut rules = Vec::new();
+        loop {
+            match self.peek() {
+                Some(t) if t.is(";") => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(_) => self.rule(&mut rules)?,
+                None => return Err(error(&kw, format!("capability {} is not closed with ;", name.text))),
+            }
+        }
+        self.out.capabilities.push(Capability { id, rules });
+        self.out.capabilities_by_name.insert(name.text, id);
+        Ok(())
+    }
+
+    /// `tag <name> id <id> [default <value>] [enum <value> <name>]... [flag <bit> <name>]... ;`
+    fn tag(&mut self) -> Result<(), RuleError> {
+        self.next("tag")?;
+        let name = self.word("a tag name")?;
+        let mut tag = NamedTag::default();
+        let mut id = None;
+        let mut default = None;
+        loop {
+            let t = self.next("a tag attribute or ;")?;
+            match t.text.as_str() {
+                ";" => break,
+                "id" => id = Some(self.number("a tag id", u64::from(u32::MAX))? as u32),
+                // may name an enum that comes later, so it's looked up at the end
+                "default" => default = Some(self.word("a default value")?),
+                "enum" => {
+                    let v = self.number("an enum value", u64::from(u32::MAX))? as u32;
+                    let n = self.word("an enum name")?;
+                    tag.enums.insert(n.text, v);
+                }
+                "flag" => {
+                    let b = self.number("a flag bit", 31)? as u32;
+                    let n = self.word("a flag name")?;
+                    tag.flags.insert(n.text, b);
+                }
+                _ => return Err(error(&t, format!("unknown tag attribute {}", t.text))),
+            }
+        }
+        tag.id = match id {
+            Some(i) => i,
+            None => return Err(error(&name, format!("tag {} needs an id", name.text))),
+        };
+        if let Some(d) = default {
+            tag.default = Some(tag_value(&d, Some(&tag))?);
+        }
+        if self.out.tags_by_name.contains_key(&name.text)
+            || self.out.tags_by_name.values().any(|t| t.id == tag.id)
+        {
+            return Err(error(&name, format!("tag {} (id {}) defined twice", name.text, tag.id)));
+        }
+        self.out.tags.push(TagDefinition {
+            id: tag.id,
+            default: tag.default,
+        });
+        self.out.tags_by_name.insert(name.text, tag);
+        Ok(())
+    }
+
+    /// An action with its matches, the matches go first in the output
+    fn rule(&mut self, rules: &mut Vec<Rules>) -> Result<(), RuleError> {
+        let t = self.word("an action")?;
+        let action = match t.text.as_str() {
+            "drop" => Rule::ActionDrop,
+            "accept" => Rule::ActionAccept,
+            "break" => Rule::ActionBreak,
+            "debug" => Rule::ActionDebugLog,
+            "tee" | "watch" => {
+                // -1 is the whole packet, which the controller keeps as 65535
+                let length = match self.peek() {
+                    Some(l) if l.is("-1") => {
+                        self.pos += 1;
+                        u16::MAX
+                    }
+                    _ => self.number("a length", u64::from(u16::MAX))? as u16,
+                };
+                let address = self.address()?;
+                let flags = self.action_flags()?;
+                if t.is("tee") {
+                    Rule::ActionTee { address, flags, length }
+                } else {
+                    Rule::ActionWatch { address, flags, length }
+                }
+            }
+            "redirect" => Rule::ActionRedirect {
+                address: self.address()?,
+                flags: self.action_flags()?,
+                length: 0,
+            },
+            _ => return Err(error(&t, format!("unknown action {}", t.text))),
+        };
+
+        let (mut not, mut or) = (false, false);
+        loop {
+            let t = self.next("a match or ;")?;
+            match t.text.as_str() {
+                ";" if not || or => return Err(error(&t, "not or or without a match".to_owned())),
+                ";" => break,
+                "and" => {}
+                "or" => or = true,
+                "not" => not = !not,
+                _ => {
+                    rules.push(Rules::with(self.matcher(&t)?, not, or));
+                    not = false;
+                    or = false;
+                }
+            }
+        }
+        rules.push(Rules::new(action));
+        Ok(())
+    }
+
+    fn address(&mut self) -> Result<NodeAddress, RuleError> {
+        let t = self.word("a zerotier address")?;
+        t.text.parse().map_err(|e: ZTError| error(&t, e.to_string()))
+    }
+
+    /// The optional `flags <n>` after the address of tee, watch and redirect
+    fn action_flags(&mut self) -> Result<u32, RuleError> {
+        match self.peek() {
+            Some(t) if t.is("flags") => {
+                self.pos += 1;
+                Ok(self.number("action flags", u64::from(u32::MAX))? as u32)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn matcher(&mut self, kw: &Token) -> Result<Rule, RuleError> {
+        Ok(match kw.text.as_str() {
+            "ztsrc" => Rule::MatchSourceZerotierAddress { zt: self.address()? },
+            "ztdest" => Rule::MatchDestZerotierAddress { zt: self.address()? },
+            "vlan" => Rule::MatchVlanId {
+                vlan_id: self.number("a vlan id", 4095)? as u16,
+            },
+            "vlanpcp" => Rule::MatchVlanPcp {
+                vlan_pcp: self.number("a vlan pcp", 7)? as u8,
+            },
+            "vlandei" => Rule::MatchVlanDei {
+                vlan_dei: self.number("a vlan dei", 1)? as u8,
+            },
+            "ethertype" => {
+                let t = self.word("an ethertype")?;
+                let ether_type = match by_name(&ETHERTYPES, &t.text) {
+                    Some(e) => e,
+                    None => parse_number(&t, "an ethertype", u64::from(u16::MAX))? as u16,
+                };
+                Rule::MatchEthertype { ether_type }
+            }
+            "macsrc" | "macdest" => {
+                let t = self.word("a mac address")?;
+                let hex: String = t.text.chars().filter(|c| *c != ':' && *c != '-').collect();
+                if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(error(&t, format!("invalid mac address {}", t.text)));
+                }
+                // the way the controller writes them
+                let octets: Vec<&str> = (0..6).map(|i| &hex[2 * i..2 * i + 2]).collect();
+                let mac = octets.join(":").to_lowercase();
+                if kw.is("macsrc") {
+                    Rule::MatchMacSource { mac }
+                } else {
+                    Rule::MatchMacDest { mac }
+                }
+            }
+            "ipsrc" | "ipdest" => {
+                let t = self.word("an ip network")?;
+                let net: IpNet = match t.text.parse::<IpNet>() {
+                    Ok(n) => n,
+                    Err(_) => match t.text.parse::<std::net::IpAddr>() {
+                        // a single address
+                        Ok(ip) => {
+                            let max = if ip.is_ipv4() { 32 } else { 128 };
+                            super::RootInterface::new_ipnet(ip, max).unwrap()
+                        }
+                        Err(_) => return Err(error(&t, format!("invalid ip network {}", t.text))),
+                    },
+                };
+                match (net, kw.is("ipsrc")) {
+                    (IpNet::V4(ip), true) => Rule::MatchIpv4Source { ip },
+                    (IpNet::V4(ip), false) => Rule::MatchIpv4Dest { ip },
+                    (IpNet::V6(ip), true) => Rule::MatchIpv6Source { ip },
+                    (IpNet::V6(ip), false) => Rule::MatchIpv6Dest { ip },
+                }
+            }
+            "iptos" => {
+                let mask = self.number("a tos mask", 255)? as u8;
+                let (start, end) = self.range("a tos range", 255)?;
+                Rule::MatchIpTos {
+                    mask,
+                    start: start as u8,
+                    end: end as u8,
+                }
+            }
+            "ipprotocol" => {
+                let t = self.word("an ip protocol")?;
+                let ip_protocol = match by_name(&IP_PROTOCOLS, &t.text) {
+                    Some(p) => p,
+                    None => parse_number(&t, "an ip protocol", 255)? as u8,
+                };
+                Rule::MatchIpProtocol { ip_protocol }
+            }
+            "icmp" => {
+                let icmp_type = self.number("an icmp type", 255)? as u8;
+                let t = self.word("an icmp code or -")?;
+                let icmp_code = if t.is("-") {
+                    None
+                } else {
+                    Some(parse_number(&t, "an icmp code", 255)? as u8)
+                };
+                Rule::MatchIcmp { icmp_type, icmp_code }
+            }
+            "sport" | "dport" | "framesize" => {
+                let (start, end) = self.range("a range", 65535)?;
+                let (start, end) = (start as u16, end as u16);
+                match kw.text.as_str() {
+                    "sport" => Rule::MatchIpSourcePortRange { start, end },
+                    "dport" => Rule::MatchIpDestPortRange { start, end },
+                    _ => Rule::MatchFrameSizeRange { start, end },
+                }
+            }
+            "chr" => {
+                let mut mask = 0u64;
+                loop {
+                    let t = self.word("a characteristic")?;
+                    mask |= match by_name(&CHARACTERISTICS, &t.text) {
+                        Some(bit) => 1 << bit,
+                        // bits without a name, as the decompiler writes them
+                        None if t.text.starts_with("0x") => parse_number(&t, "a characteristic", u64::MAX)?,
+                        None => return Err(error(&t, format!("unknown characteristic {}", t.text))),
+                    };
+                    match self.peek() {
+                        Some(c) if c.is(",") => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                Rule::MatchCharacteristics { mask }
+            }
+            "random" => {
+                let t = self.word("a probability")?;
+                let p: f64 = match t.text.parse() {
+                    Ok(p) if (0.0..=1.0).contains(&p) => p,
+                    _ => return Err(error(&t, format!("probability {} is not within 0 and 1", t.text))),
+                };
+                // rounded, so what the decompiler prints comes back the same
+                Rule::MatchRandom {
+                    probability: (p * f64::from(u32::MAX)).round() as u32,
+                }
+            }
+            "tand" | "tor" | "txor" | "tdiff" | "teq" | "tseq" | "treq" => {
+                let t = self.word("a tag")?;
+                let tag = self.out.tags_by_name.get(&t.text).cloned();
+                let id = match tag {
+                    Some(ref tag) => tag.id,
+                    None => parse_number(&t, "a tag id or name", u64::from(u32::MAX))? as u32,
+                };
+                let v = self.word("a tag value")?;
+                let value = tag_value(&v, tag.as_ref())?;
+                match kw.text.as_str() {
+                    "tand" => Rule::MatchTagsBitwiseAnd { id, value },
+                    "tor" => Rule::MatchTagsBitwiseOr { id, value },
+                    "txor" => Rule::MatchTagsBitwiseXor { id, value },
+                    "tdiff" => Rule::MatchTagsDifference { id, value },
+                    "teq" => Rule::MatchTagsEqual { id, value },
+                    "tseq" => Rule::MatchTagSender { id, value },
+                    _ => Rule::MatchTagReceiver { id, value },
+                }
+            }
+            _ => return Err(error(kw, format!("unknown match {}", kw.text))),
+        })
+    }
+
+    /// `n` or `n-m`
+    fn range(&mut self, wanted: &str, max: u64) -> Result<(u64, u64), RuleError> {
+        let t = self.word(wanted)?;
+        let mut parts = t.text.splitn(2, '-');
+        let start = parts.next().unwrap_or("");
+        let start = parse_number(&Token { text: start.to_owned(), ..t.clone() }, wanted, max)?;
+        let end = match parts.next() {
+            Some(e) => parse_number(&Token { text: e.to_owned(), ..t.clone() }, wanted, max)?,
+            None => start,
+        };
+        if end < start {
+            return Err(error(&t, format!("range {} runs backwards", t.text)));
+        }
+        Ok((start, end))
+    }
+}
+
+/// Decimal, or hex with `0x`
+pub(crate) fn parse_number(t: &Token, wanted: &str, max: u64) -> Result<u64, RuleError> {
+    let n = match t.text.strip_prefix("0x") {
+        Some(h) => u64::from_str_radix(h, 16),
+        None => t.text.parse(),
+    };
+    match n {
+        Ok(n) if n <= max => Ok(n),
+        Ok(_) => Err(error(t, format!("{} is too big for {}", t.text, wanted))),
+        Err(_) => Err(error(t, format!("expected {}, got {}", wanted, t.text))),
+    }
+}
+
+/// A number, or an enum or flag name of the tag
+pub(crate) fn tag_value(t: &Token, tag: Option<&NamedTag>) -> Result<u32, RuleError> {
+    if let Some(tag) = tag {
+        if let Some(v) = tag.enums.get(&t.text) {
+            return Ok(*v);
+        }
+        if let Some(b) = tag.flags.get(&t.text) {
+            return Ok(1 << b);
+        }
+    }
+    Ok(parse_number(t, "a tag value", u64::from(u32::MAX))? as u32)
+}
+
+/// The names the language has for ethertypes
+pub(crate) const ETHERTYPES: [(&str, u16); 5] = [
+    ("ipv4", ZT_ETHERTYPE_IPV4),
+    ("arp", ZT_ETHERTYPE_ARP),
+    ("ipv6", ZT_ETHERTYPE_IPV6),
+    ("wol", 0x0842),
+    ("rarp", 0x8035),
+];
+
+/// IP protocol names, the aliases after the names we write
+pub(crate) const IP_PROTOCOLS: [(&str, u8); 11] = [
+    ("icmp", 1),
+    ("igmp", 2),
+    ("tcp", 6),
+    ("udp", 17),
+    ("gre", 47),
+    ("esp", 50),
+    ("ah", 51),
+    ("icmp6", 58),
+    ("sctp", 132),
+    ("icmp4", 1),
+    ("icmpv6", 58),
+];
+
+/// The packet characteristics bits, as zerotier numbers them
+pub(crate) const CHARACTERISTICS: [(&str, u32); 17] = [
+    ("inbound", 63),
+    ("multicast", 62),
+    ("broadcast", 61),
+    ("ipauth", 60),
+    ("macauth", 59),
+    ("tcp_rs2", 11),
+    ("tcp_rs1", 10),
+    ("tcp_rs0", 9),
+    ("tcp_ns", 8),
+    ("tcp_cwr", 7),
+    ("tcp_ece", 6),
+    ("tcp_urg", 5),
+    ("tcp_ack", 4),
+    ("tcp_psh", 3),
+    ("tcp_rst", 2),
+    ("tcp_syn", 1),
+    ("tcp_fin", 0),
+];
+
+pub(crate) fn by_name<T: Copy>(table: &[(&str, T)], name: &str) -> Option<T> {
+    table.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+}
+
+fn by_value<T: Copy + PartialEq>(table: &[(&'static str, T)], value: T) -> Option<&'static str> {
+    table.iter().find(|(_, v)| *v == value).map(|(n, _)| *n)
+}
+
+/// The rules, capabilities and tags of `net` as rules source, in the form
+/// `compile` takes back. The controller doesn't know the names they had,
+/// so tags and capabilities are called after their ids.
+pub fn decompile(net: &RootInterface) -> String {
+    let mut out = String::new();
+    for t in &net.tags {
+        match t.default {
+            Some(d) => out.push_str(&format!("tag tag_{} id {} default {};\n", t.id, t.id, d)),
+            None => out.push_str(&format!("tag tag_{} id {};\n", t.id, t.id)),
+        }
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    for c in &net.capabilities {
+        out.push_str(&format!("cap cap_{}\n  id {}\n", c.id, c.id));
+        for line in decompile_rules(&c.rules).lines() {
+            out.push_str(&format!("  {}\n", line));
+        }
+        out.push_str(";\n\n");
+    }
+    out.push_str(&decompile_rules(&net.rules));
+    out
+}
+
+/// One line per rule, its action first
+pub fn decompile_rules(rules: &[Rules]) -> String {
+    let mut out = String::new();
+    let mut matches: Vec<String> = Vec::new();
+    let mut known = true;
+    for r in rules {
+        if !r.rule.is_action() {
+            let mut m = String::new();
+            if r.ror {
+                m.push_str("or ");
+            } else if !matches.is_empty() {
+                m.push_str("and ");
+            }
+            if r.rnot {
+                m.push_str("not ");
+            }
+            match match_source(&r.rule) {
+                Some(s) => m.push_str(&s),
+                // nothing in the language for it, so the rule can only be
+                // a comment saying what it was
+                None => {
+                    m.push_str(&serde_json::to_string(r).unwrap_or_default());
+                    known = false;
+                }
+            }
+            matches.push(m);
+            continue;
+        }
+        let mut line = match action_source(&r.rule) {
+            Some(a) => a,
+            None => {
+                known = false;
+                serde_json::to_string(r).unwrap_or_default()
+            }
+        };
+        for m in matches.drain(..) {
+            line.push(' ');
+            line.push_str(&m);
+        }
+        if known {
+            out.push_str(&format!("{};\n", line));
+        } else {
+            out.push_str(&format!("# {}\n", line));
+        }
+        known = true;
+    }
+    // matches after the last action never decide anything
+    if !matches.is_empty() {
+        out.push_str(&format!("# without action: {}\n", matches.join(" ")));
+    }
+    out
+}
+
+fn action_source(rule: &Rule) -> Option<String> {
+    Some(match rule {
+        Rule::ActionDrop => "drop".to_owned(),
+        Rule::ActionAccept => "accept".to_owned(),
+        Rule::ActionBreak => "break".to_owned(),
+        Rule::ActionDebugLog => "debug".to_owned(),
+        Rule::ActionTee { address, flags, length } => {
+            format!("tee {} {}{}", tee_length(*length), address, action_flags(*flags))
+        }
+        Rule::ActionWatch { address, flags, length } => {
+            format!("watch {} {}{}", tee_length(*length), address, action_flags(*flags))
+        }
+        Rule::ActionRedirect { address, flags, .. } => format!("redirect {}{}", address, action_flags(*flags)),
+        _ => return None,
+    })
+}
+
+fn action_flags(flags: u32) -> String {
+    if flags == 0 {
+        String::new()
+    } else {
+        format!(" flags {:#x}", flags)
+    }
+}
+
+fn tee_length(length: u16) -> String {
+    if length == u16::MAX {
+        "-1".to_owned()
+    } else {
+        length.to_string()
+    }
+}
+
+fn range(start: u64, end: u64) -> String {
+    if start == end {
+        start.to_string()
+    } else {
+        format!("{}-{}", start, end)
+    }
+}
+
+fn match_source(rule: &Rule) -> Option<String> {
+    Some(match rule {
+        Rule::MatchSourceZerotierAddress { zt } => format!("ztsrc {}", zt),
+        Rule::MatchDestZerotierAddress { zt } => format!("ztdest {}", zt),
+        Rule::MatchVlanId { vlan_id } => format!("vlan {}", vlan_id),
+        Rule::MatchVlanPcp { vlan_pcp } => format!("vlanpcp {}", vlan_pcp),
+        Rule::MatchVlanDei { vlan_dei } => format!("vlandei {}", vlan_dei),
+        Rule::MatchEthertype { ether_type } => match by_value(&ETHERTYPES, *ether_type) {
+            Some(n) => format!("ethertype {}", n),
+            None => format!("ethertype 0x{:04x}", ether_type),
+        },
+        Rule::MatchMacSource { mac } => format!("macsrc {}", mac),
+        Rule::MatchMacDest { mac } => format!("macdest {}", mac),
+        Rule::MatchIpv4Source { ip } => format!("ipsrc {}", ip),
+        Rule::MatchIpv4Dest { ip } => format!("ipdest {}", ip),
+        Rule::MatchIpv6Source { ip } => format!("ipsrc {}", ip),
+        Rule::MatchIpv6Dest { ip } => format!("ipdest {}", ip),
+        Rule::MatchIpTos { mask, start, end } => {
+            format!("iptos {} {}", mask, range(u64::from(*start), u64::from(*end)))
+        }
+        Rule::MatchIpProtocol { ip_protocol } => match by_value(&IP_PROTOCOLS, *ip_protocol) {
+            Some(n) => format!("ipprotocol {}", n),
+            None => format!("ipprotocol {}", ip_protocol),
+        },
+        Rule::MatchIcmp { icmp_type, icmp_code } => match icmp_code {
+            Some(c) => format!("icmp {} {}", icmp_type, c),
+            None => format!("icmp {} -", icmp_type),
+        },
+        Rule::MatchIpSourcePortRange { start, end } => {
+            format!("sport {}", range(u64::from(*start), u64::from(*end)))
+        }
+        Rule::MatchIpDestPortRange { start, end } => {
+            format!("dport {}", range(u64::from(*start), u64::from(*end)))
+        }
+        Rule::MatchFrameSizeRange { start, end } => {
+            format!("framesize {}", range(u64::from(*start), u64::from(*end)))
+        }
+        Rule::MatchCharacteristics { mask } => {
+            let mut names: Vec<String> = Vec::new();
+            let mut rest = *mask;
+            for (n, bit) in CHARACTERISTICS.iter() {
+                if mask & (1 << bit) != 0 {
+                    names.push((*n).to_owned());
+                    rest &= !(1 << bit);
+                }
+            }
+            if rest != 0 || names.is_empty() {
+                names.push(format!("0x{:x}", rest));
+            }
+            format!("chr {}", names.join(","))
+        }
+        Rule::MatchRandom { probability } => {
+            format!("random {}", f64::from(*probability) / f64::from(u32::MAX))
+        }
+        Rule::MatchTagsDifference { id, value } => format!("tdiff {} {}", id, value),
+        Rule::MatchTagsBitwiseAnd { id, value } => format!("tand {} {}", id, value),
+        Rule::MatchTagsBitwiseOr { id, value } => format!("tor {} {}", id, value),
+        Rule::MatchTagsBitwiseXor { id, value } => format!("txor {} {}", id, value),
+        Rule::MatchTagsEqual { id, value } => format!("teq {} {}", id, value),
+        Rule::MatchTagSender { id, value } => format!("tseq {} {}", id, value),
+        Rule::MatchTagReceiver { id, value } => format!("treq {} {}", id, value),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn types(rules: &[Rules]) -> Vec<String> {
+        rules
+            .iter()
+            .map(|r| serde_json::to_value(r).unwrap()["type"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_compile() -> Result<(), RuleError> {
+        let c = compile(
+            "# default zerotier policy
+            drop
+                not ethertype ipv4
+                and not ethertype arp
+                and not ethertype ipv6
+            ;
+            tag role id 1000 enum 10 admin enum 20 user default user;
+            macro allow_port($port)
+              accept ipprotocol tcp and dport $port;
+            ;
+            cap ssh
+              id 2000
+              include allow_port(22);
+            ;
+            tee 128 deadbeef00 ipsrc 10.1.0.0/16 or ipdest 10.1.0.1;
+            drop chr tcp_syn,tcp_ack and teq role admin;
+            accept;",
+        );
+        let c = match c {
+            Ok(c) => c,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(
+            types(&c.rules),
+            vec![
+                "MATCH_ETHERTYPE",
+                "MATCH_ETHERTYPE",
+                "MATCH_ETHERTYPE",
+                "ACTION_DROP",
+                "MATCH_IPV4_SOURCE",
+                "MATCH_IPV4_DEST",
+                "ACTION_TEE",
+                "MATCH_CHARACTERISTICS",
+                "MATCH_TAGS_EQUAL",
+                "ACTION_DROP",
+                "ACTION_ACCEPT",
+            ]
+        );
+        assert_eq!(c.rules[1], Rules::with(Rule::MatchEthertype { ether_type: ZT_ETHERTYPE_ARP }, true, false));
+        assert!(c.rules[5].ror);
+        assert_eq!(c.rules[5].rule, Rule::MatchIpv4Dest { ip: "10.1.0.1/32".parse().unwrap() });
+        assert_eq!(c.rules[7].rule, Rule::MatchCharacteristics { mask: 0x12 });
+        assert_eq!(c.rules[8].rule, Rule::MatchTagsEqual { id: 1000, value: 10 });
+        assert_eq!(c.tags, vec![TagDefinition { id: 1000, default: Some(20) }]);
+        assert_eq!(c.capabilities_by_name["ssh"], 2000);
+        let cap = &c.capabilities[0].rules;
+        assert_eq!(cap[1].rule, Rule::MatchIpDestPortRange { start: 22, end: 22 });
+        assert_eq!(cap[2].rule, Rule::ActionAccept);
+
+        let c = compile("watch -1 deadbeef00;").unwrap();
+        assert_eq!(c.rules[0].rule, Rule::ActionWatch { address: "deadbeef00".parse().unwrap(), flags: 0, length: u16::MAX });
+        assert!(compile("tee -2 deadbeef00;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let at = |src: &str| compile(src).map(|_| ()).unwrap_err();
+        let e = at("accept;\ndrop ethertype ipv5;");
+        assert_eq!((e.line, e.column), (2, 16));
+        assert_eq!(at("  frobnicate;").column, 3);
+        assert_eq!(at("accept dport 80-22;").column, 14);
+        assert_eq!(at("accept not;").column, 11);
+        let e = at("drop ipsrc 10.0.0.1");
+        assert_eq!((e.line, e.column), (1, 20));
+        assert!(at("include nope();").message.contains("no macro"));
+        assert!(at("macro m()\n include m();\n;\ninclude m();").message.contains("too deep"));
+        assert!(at("cap x id 1 accept;").message.contains("not closed"));
+        assert_eq!(ZTError::from(at("drop teq 1 two;")).code(), 151);
+    }
+
+    #[test]
+    fn test_decompile() -> Result<(), ZTError> {
+        let source = "tag role id 1000 enum 10 admin enum 20 user default 20;\n\
+                      cap ssh id 2000 accept ipprotocol tcp and dport 22;\n;\n\
+                      drop not ethertype ipv4 and not ethertype arp and not ethertype 0x88cc;\n\
+                      tee 128 8056c2e21c chr tcp_syn,inbound or icmp 8 -;\n\
+                      watch -1 8056c2e21c ipprotocol udp;\n\
+                      break ipsrc 10.1.0.0/16 and teq role admin and sport 1024-65535;\n\
+                      accept;\n";
+        let mut net = RootInterface::default();
+        compile(source)?.apply_to(&mut net);
+        let text = decompile(&net);
+        assert!(text.contains("ethertype ipv4"));
+        assert!(text.contains("ethertype 0x88cc"));
+        assert!(text.contains("chr inbound,tcp_syn"));
+        assert!(text.contains("watch -1 8056c2e21c"));
+
+        let mut again = RootInterface::default();
+        compile(&text)?.apply_to(&mut again);
+        assert_eq!(again.rules, net.rules);
+        assert_eq!(again.capabilities, net.capabilities);
+        assert_eq!(again.tags, net.tags);
+
+        // no source for an integer range, the rule stays as a comment
+        let rules: Vec<Rules> = serde_json::from_value(json!([
+            {"type": "MATCH_INTEGER_RANGE", "not": false, "or": false, "start": "0000000000000001",
+             "end": "0000000000000002", "idx": 0, "little": false, "bits": 8},
+            {"type": "ACTION_DROP", "not": false, "or": false},
+            {"type": "ACTION_ACCEPT", "not": false, "or": false}
+        ]))
+        .unwrap();
+        let text = decompile_rules(&rules);
+        assert!(text.starts_with("# drop {"));
+        assert_eq!(compile(&text)?.rules, vec![Rules::new(Rule::ActionAccept)]);
+
+        for &probability in &[0, 1, 3, 7, 1 << 31, u32::MAX / 3, u32::MAX / 7 * 5, u32::MAX - 1, u32::MAX] {
+            let rules = vec![Rules::new(Rule::MatchRandom { probability }), Rules::new(Rule::ActionDrop)];
+            assert_eq!(compile(&decompile_rules(&rules))?.rules, rules);
+        }
+        // half of u32::MAX is x.5, the nearest wins rather than the one below
+        let half = compile("drop random 0.5;")?;
+        assert_eq!(half.rules[0].rule, Rule::MatchRandom { probability: 1 << 31 });
+
+        let priority = Rules::new(Rule::ActionPriority { qos_bucket: 2 });
+        let text = decompile_rules(&[priority]);
+        assert!(text.starts_with("# {") && text.contains("\"qosBucket\":2"));
+
+        // flags are kept, and only written when there are some
+        let rules = vec![
+            Rules::new(Rule::ActionTee { address: "8056c2e21c".parse().unwrap(), flags: 0x2, length: 64 }),
+            Rules::new(Rule::ActionWatch { address: "8056c2e21c".parse().unwrap(), flags: 0x10, length: 0 }),
+            Rules::new(Rule::ActionRedirect { address: "deadbeef00".parse().unwrap(), flags: 0x1, length: 0 }),
+            Rules::new(Rule::ActionRedirect { address: "deadbeef00".parse().unwrap(), flags: 0, length: 0 }),
+        ];
+        let text = decompile_rules(&rules);
+        assert!(text.contains("tee 64 8056c2e21c flags 0x2;"));
+        assert!(text.contains("redirect deadbeef00;"));
+        assert_eq!(compile(&text)?.rules, rules);
+        Ok(())
+    }
+}
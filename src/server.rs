@@ -0,0 +1,394 @@
+//! A small REST api in front of the controller, so other services can
+//! manage networks, routes, members and the moon without holding the
+//! daemon's auth token. It goes through the same `ControllerClient` as the
+//! cli does, and answers JSON.
+//!
+//! ```text
+//!     POST   /networks                              {name, private, start, end, mask}
+//!     GET    /networks/<nwid>
+//!     DELETE /networks/<nwid>
+//!     POST   /networks/<nwid>/subnets               {start, end, mask}
+//!     POST   /networks/<nwid>/routes                {target, via}
+//!     DELETE /networks/<nwid>/routes/<ip>/<mask>
+//!     GET    /networks/<nwid>/members
+//!     GET    /networks/<nwid>/members/<id>
+//!     POST   /networks/<nwid>/members/<id>          {authorized}
+//!     DELETE /networks/<nwid>/members/<id>
+//!     POST   /moon                                  {endpoints}
+//! ```
+//!
+//! Every request needs a bearer JWT whose scopes cover the endpoint, see
+//! the jwt module for those. Failures come back as
+//! `{"code": <ZTError code>, "error": "..."}`, a missing or bad token is a
+//! 401 with code 312, a token without the right scope a 403 with code 313.
+
+extern crate tiny_http;
+
+use failure::Error;
+use ipnet::IpNet;
+use serde_json::{json, Value};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use super::commands::ControllerClient;
+#[cfg(test)]
+use super::jwt;
+use super::jwt::JwtAuth;
+#[cfg(test)]
+use super::mock;
+use super::moon;
+use super::{RootInterface, ZTError};
+
+#[derive(Deserialize)]
+struct NewNetwork {
+    name: Option<String>,
+    #[serde(default = "default_private")]
+    private: bool,
+    start: IpAddr,
+    end: IpAddr,
+    mask: u8,
+}
+
+fn default_private() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct Subnet {
+    start: IpAddr,
+    end: IpAddr,
+    mask: u8,
+}
+
+#[derive(Deserialize)]
+struct NewRoute {
+    target: IpNet,
+    via: Option<IpAddr>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    authorized: bool,
+}
+
+#[derive(Deserialize)]
+struct NewMoon {
+    endpoints: Vec<String>,
+}
+
+/// The api, bound to one controller and one zerotier home (for the moon),
+/// trusting the tokens that `jwt` verifies
+pub struct ApiServer {
+    client: ControllerClient,
+    home: PathBuf,
+    jwt: JwtAuth,
+}
+
+impl ApiServer {
+    pub fn new(client: ControllerClient, home: PathBuf, jwt: JwtAuth) -> Self {
+        ApiServer { client, home, jwt }
+    }
+
+    /// Listen on `addr` (e.g. `127.0.0.1:8080`) and answer requests, one
+    /// at a time, until the process goes away
+    pub fn serve(&self, addr: &str) -> Result<(), Error> {
+        let server = tiny_http::Server::http(addr).map_err(Error::from_boxed_compat)?;
+        for mut req in server.incoming_requests() {
+            let mut body = String::new();
+            let authorization = req
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.as_str().to_owned());
+            let (code, v) = match req.as_reader().read_to_string(&mut body) {
+                Ok(_) => self.handle(
+                    req.method().as_str(),
+                    req.url(),
+                    authorization.as_deref(),
+                    &body,
+                ),
+                Err(e) => error_response(&bad_request(&e.to_string()).into()),
+            };
+            let resp = tiny_http::Response::from_string(v.to_string())
+                .with_status_code(code)
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                        .unwrap(),
+                );
+            let _ = req.respond(resp);
+        }
+        Ok(())
+    }
+
+    /// One request in, status and JSON out. No sockets involved, so it's
+    /// what the tests talk to.
+    pub fn handle(
+        &self,
+        method: &str,
+        url: &str,
+        authorization: Option<&str>,
+        body: &str,
+    ) -> (u16, Value) {
+        match self.route(method, url, authorization, body) {
+            Ok(v) => (200, v),
+            Err(e) => error_response(&e),
+        }
+    }
+
+    fn route(
+        &self,
+        method: &str,
+        url: &str,
+        authorization: Option<&str>,
+        body: &str,
+    ) -> Result<Value, Error> {
+        // ids are hex, routed on the lower case form; scopes ignore case
+        let path = url.split('?').next().unwrap_or("").to_lowercase();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let method = method.to_uppercase();
+        // who's asking goes before what's asked, also for unknown endpoints
+        let claims = self.jwt.verify(authorization)?;
+        if let Some(scope) = required_scope(&method, &segments) {
+            claims.require(&scope)?;
+        }
+        let c = &self.client;
+        match (method.as_str(), &segments[..]) {
+            ("POST", ["networks"]) => {
+                let n: NewNetwork = parse_body(body)?;
+                let r = RootInterface::with(n.name, n.private, n.start, n.end, n.mask, None)?;
+                Ok(json!(c.new_network(&r)?))
+            }
+            ("GET", ["networks", nwid]) => Ok(json!(c.get_network(nwid.parse()?)?)),
+            ("DELETE", ["networks", nwid]) => {
+                c.delete_network(nwid.parse()?)?;
+                Ok(json!({}))
+            }
+            ("POST", ["networks", nwid, "subnets"]) => {
+                let s: Subnet = parse_body(body)?;
+                let mut net = c.get_network(nwid.parse()?)?;
+                net.add_subnet(s.start, s.end, s.mask)?;
+                Ok(json!(c.update_network_checked(&net)?))
+            }
+            ("POST", ["networks", nwid, "routes"]) => {
+                let r: NewRoute = parse_body(body)?;
+                let mut net = c.get_network(nwid.parse()?)?;
+                net.add_route(r.target, r.via)?;
+                Ok(json!(c.update_network_checked(&net)?))
+            }
+            ("DELETE", ["networks", nwid, "routes", ip, mask]) => {
+                let target: IpNet = match format!("{}/{}", ip, mask).parse() {
+                    Ok(t) => t,
+                    Err(_) => return Err(bad_request("invalid route target").into()),
+                };
+                let mut net = c.get_network(nwid.parse()?)?;
+                net.del_route(target)?;
+                Ok(json!(c.update_network_checked(&net)?))
+            }
+            ("GET", ["networks", nwid, "members"]) => Ok(json!(c.list_members(nwid.parse()?)?)),
+            ("GET", ["networks", nwid, "members", id]) => Ok(json!(c.get_member(nwid.parse()?, id.parse()?)?)),
+            ("POST", ["networks", nwid, "members", id]) => {
+                let a: Authorization = parse_body(body)?;
+                let m = if a.authorized {
+                    c.authorize_member(nwid.parse()?, id.parse()?)?
+                } else {
+                    c.deauthorize_member(nwid.parse()?, id.parse()?)?
+                };
+                Ok(json!(m))
+            }
+            ("DELETE", ["networks", nwid, "members", id]) => {
+                c.delete_member(nwid.parse()?, id.parse()?)?;
+                Ok(json!({}))
+            }
+            ("POST", ["moon"]) => {
+                let m: NewMoon = parse_body(body)?;
+                let mut endpoints = Vec::new();
+                for ep in &m.endpoints {
+                    endpoints.push(moon::parse_endpoint(ep)?);
+                }
+                let moon = moon::deploy_local(&self.home, &endpoints)?;
+                // never hand out the signing key
+                Ok(json!({ "id": moon.seed(), "roots": moon.roots }))
+            }
+            _ => Err(ZTError {
+                code: 311i32,
+                message: format!("no such endpoint {} {}", method, path),
+            }
+            .into()),
+        }
+    }
+}
+
+/// The scope an endpoint wants, `None` for the ones we don't have
+fn required_scope(method: &str, segments: &[&str]) -> Option<String> {
+    let scope = match (method, segments) {
+        ("POST", ["networks"]) => "network:create".to_owned(),
+        ("GET", ["networks", nwid]) => format!("network:{}:read", nwid),
+        ("DELETE", ["networks", nwid])
+        | ("POST", ["networks", nwid, "subnets"])
+        | ("POST", ["networks", nwid, "routes"])
+        | ("DELETE", ["networks", nwid, "routes", _, _]) => format!("network:{}:write", nwid),
+        ("GET", ["networks", nwid, "members"]) | ("GET", ["networks", nwid, "members", _]) => {
+            format!("network:{}:members:read", nwid)
+        }
+        ("POST", ["networks", nwid, "members", _]) | ("DELETE", ["networks", nwid, "members", _]) => {
+            format!("network:{}:members:write", nwid)
+        }
+        ("POST", ["moon"]) => "moon:write".to_owned(),
+        _ => return None,
+    };
+    Some(scope)
+}
+
+fn bad_request(message: &str) -> ZTError {
+    ZTError {
+        code: 310i32,
+        message: message.to_owned(),
+    }
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(body: &str) -> Result<T, Error> {
+    match serde_json::from_str(body) {
+        Ok(t) => Ok(t),
+        Err(e) => Err(bad_request(&format!("invalid request body: {}", e)).into()),
+    }
+}
+
+/// Library errors are about what was asked, so 400; a network or member the
+/// controller doesn't have is a 404, the controller failing otherwise (or
+/// not answering) a 502, a network found changed since it was read a 409
+/// (see `update_network_checked`, it can't catch every race), and the
+/// token checks give 401 and 403
+fn error_response(e: &Error) -> (u16, Value) {
+    match e.downcast_ref::<ZTError>() {
+        Some(zte) => {
+            let status = match zte.code {
+                // the controller didn't give us what a network needs
+                103 | 301 => 502,
+                // our own auth or a bug on our side, nothing the caller can fix
+                104 | 105 | 131..=134 => 500,
+                // what the caller sent: addresses, pools, routes, ids, rules
+                101 | 106..=127 | 141..=143 | 151..=199 | 310 => 400,
+                302 => 409,
+                303 | 311 => 404,
+                312 => 401,
+                313 => 403,
+                _ => 500,
+            };
+            (status, json!({ "code": zte.code, "error": zte.message }))
+        }
+        // can't reach the controller at all
+        None if e.downcast_ref::<reqwest::Error>().is_some() => {
+            (502, json!({ "code": 300, "error": e.to_string() }))
+        }
+        None => (500, json!({ "code": 300, "error": e.to_string() })),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jwt::test::token;
+    use mock::MockController;
+
+    #[test]
+    fn test_api() -> Result<(), Error> {
+        let mock = MockController::start("8056c2e21c", "sikrit")?;
+        let api = ApiServer::new(
+            mock.client()?,
+            std::env::temp_dir(),
+            JwtAuth::hs256(b"api secret"),
+        );
+        let admin = token(b"api secret", &["network:*", "network:create"]);
+        let admin = Some(admin.as_str());
+
+        let (code, net) = api.handle(
+            "POST",
+            "/networks",
+            admin,
+            r#"{"name":"api","start":"10.1.1.10","end":"10.1.1.20","mask":24}"#,
+        );
+        assert_eq!(code, 200);
+        let nwid = net["nwid"].as_str().unwrap().to_owned();
+
+        let (code, _) = api.handle(
+            "POST",
+            &format!("/networks/{}/routes", nwid),
+            admin,
+            r#"{"target":"172.22.2.0/24","via":"10.1.1.1"}"#,
+        );
+        assert_eq!(code, 200);
+        let (code, e) = api.handle(
+            "POST",
+            &format!("/networks/{}/routes", nwid),
+            admin,
+            r#"{"target":"172.22.3.0/24","via":"10.1.2.1"}"#,
+        );
+        assert_eq!((code, e["code"].as_i64()), (400, Some(101)));
+        let (code, net) = api.handle(
+            "DELETE",
+            &format!("/networks/{}/routes/172.22.2.0/24", nwid),
+            admin,
+            "",
+        );
+        assert_eq!(code, 200);
+        assert_eq!(net["routes"].as_array().unwrap().len(), 1);
+
+        mock.join(nwid.parse()?, "deadbeef00".parse()?);
+        let (code, m) = api.handle(
+            "POST",
+            &format!("/networks/{}/members/deadbeef00", nwid),
+            admin,
+            r#"{"authorized":true}"#,
+        );
+        assert_eq!((code, m["authorized"].as_bool()), (200, Some(true)));
+
+        assert_eq!(api.handle("POST", "/networks", admin, "{").0, 400);
+        assert_eq!(api.handle("GET", "/nowhere", admin, "").0, 404);
+        let (code, e) = api.handle("GET", "/networks/8056c2e21c000000", admin, "");
+        assert_eq!((code, e["code"].as_i64()), (404, Some(303)));
+        // not an id, so it never gets to the controller
+        assert_eq!(api.handle("GET", "/networks/8056c2e21c", admin, "").0, 400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_status() {
+        let status = |code| error_response(&ZTError::new(code, "").into()).0;
+        assert_eq!(status(117), 400);
+        assert_eq!(status(142), 400);
+        assert_eq!(status(103), 502);
+        assert_eq!(status(133), 500);
+    }
+
+    #[test]
+    fn test_api_scopes() -> Result<(), Error> {
+        let mock = MockController::start("8056c2e21c", "sikrit")?;
+        let api = ApiServer::new(
+            mock.client()?,
+            std::env::temp_dir(),
+            JwtAuth::hs256(b"api secret"),
+        );
+        let nwid = mock.client()?.new_network(&RootInterface::default())?.nwid.unwrap();
+        let network = format!("/networks/{}", nwid);
+        let member = format!("/networks/{}/members/deadbeef00", nwid);
+        let tenant = token(b"api secret", &[&format!("network:{}:members", nwid)]);
+        let tenant = Some(tenant.as_str());
+
+        let (code, e) = api.handle("GET", &network, None, "");
+        assert_eq!((code, e["code"].as_i64()), (401, Some(312)));
+        let (code, e) = api.handle("GET", &network, tenant, "");
+        assert_eq!((code, e["code"].as_i64()), (403, Some(313)));
+        let other = "/networks/8056c2e21c000000/members/deadbeef00";
+        assert_eq!(api.handle("POST", other, tenant, r#"{"authorized":true}"#).0, 403);
+        assert_eq!(api.handle("POST", "/moon", tenant, r#"{"endpoints":[]}"#).0, 403);
+
+        mock.join(nwid, "deadbeef00".parse()?);
+        assert_eq!(api.handle("POST", &member, tenant, r#"{"authorized":true}"#).0, 200);
+
+        // however the nwid is written, in the token or the path
+        let upper = token(b"api secret", &[&format!("network:{}:members", nwid.to_string().to_uppercase())]);
+        assert_eq!(api.handle("GET", &member, Some(&upper), "").0, 200);
+        assert_eq!(api.handle("GET", &member.to_uppercase(), Some(&upper), "").0, 200);
+        Ok(())
+    }
+}
@@ -0,0 +1,502 @@
+//! Run a frame through a network's rules without a network: what the
+//! controller would make of it, and which rule decided.
+//!
+//! Evaluation follows the zerotier node. Rules go in order; matches before
+//! an action are and-ed (or or-ed) together, and the action applies when
+//! they hold. `drop`, `accept` and `redirect` decide, `tee` and `watch` copy
+//! the frame and go on, `break` ends the rule set undecided. An undecided
+//! frame goes through the sender's capabilities in turn, the first that
+//! accepts (or redirects) lets it through; a drop in a capability only ends
+//! that capability. Whatever is still undecided after that is dropped.
+//!
+//! Test cases are written like rules, a verdict with the frame it's for:
+//!
+//! ```text
+//!     accept when ethertype ipv4 ipprotocol tcp dport 22 cap ssh;
+//!     drop when ethertype ipv4 and ipprotocol tcp and dport 22;
+//!     accept tee 8056c2e21c when ethertype arp stag role admin;
+//!     redirect 8056c2e21c when ethertype ipv6 ipdest fd00::1;
+//! ```
+//!
+//! The frame is described by the words the matches use (`ethertype`,
+//! `ztsrc`, `ztdest`, `macsrc`, `macdest`, `ipsrc`, `ipdest`, `iptos`,
+//! `ipprotocol`, `icmp <type> <code>`, `sport`, `dport`, `framesize`,
+//! `vlan`, `vlanpcp`, `vlandei`, `chr`, `random`), plus `stag <tag> <value>`
+//! and `rtag <tag> <value>` for the sender's and receiver's tags and
+//! `cap <capability>` for the sender's capabilities; `and` may go between
+//! them. Tags and capabilities are named as in the rules source.
+
+use ipnet::IpNet;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use super::rules::{
+    by_name, error, lex, parse_number, tag_value, CompiledRules, RuleError, Token, CHARACTERISTICS, ETHERTYPES,
+    IP_PROTOCOLS,
+};
+use super::{NodeAddress, RootInterface, Rule, Rules, ZT_ETHERTYPE_IPV4, ZT_ETHERTYPE_IPV6};
+
+/// A frame as the rules see it, `None` for what it doesn't have
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Frame {
+    pub ether_type: u16,
+    pub zt_source: Option<NodeAddress>,
+    pub zt_dest: Option<NodeAddress>,
+    pub mac_source: Option<String>,
+    pub mac_dest: Option<String>,
+    pub ip_source: Option<IpAddr>,
+    pub ip_dest: Option<IpAddr>,
+    pub ip_tos: Option<u8>,
+    pub ip_protocol: Option<u8>,
+    /// Type and code
+    pub icmp: Option<(u8, u8)>,
+    pub source_port: Option<u16>,
+    pub dest_port: Option<u16>,
+    pub size: u16,
+    pub vlan_id: u16,
+    pub vlan_pcp: u8,
+    pub vlan_dei: u8,
+    /// The characteristics bits, see `chr`
+    pub characteristics: u64,
+    /// Stands in for the node's random number, `random` matches below it
+    pub random: u32,
+    pub sender_tags: BTreeMap<u32, u32>,
+    pub receiver_tags: BTreeMap<u32, u32>,
+    pub sender_capabilities: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Accept,
+    Drop,
+    Redirect(NodeAddress),
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::Accept => write!(f, "accept"),
+            Verdict::Drop => write!(f, "drop"),
+            Verdict::Redirect(a) => write!(f, "redirect {}", a),
+        }
+    }
+}
+
+/// The rule that decided: the nth (from 1) rule of the network, or of one
+/// of its capabilities
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decider {
+    pub capability: Option<u32>,
+    pub rule: usize,
+}
+
+impl fmt::Display for Decider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.capability {
+            Some(c) => write!(f, "rule {} of cap {}", self.rule, c),
+            None => write!(f, "rule {}", self.rule),
+        }
+    }
+}
+
+/// What became of a frame. Without a decider, no rule decided and it was
+/// dropped. `copies` are the nodes `tee` and `watch` sent it to on the way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub verdict: Verdict,
+    pub decider: Option<Decider>,
+    pub copies: Vec<NodeAddress>,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.verdict)?;
+        for c in &self.copies {
+            write!(f, " tee {}", c)?;
+        }
+        match self.decider {
+            Some(d) => write!(f, " ({})", d),
+            None => write!(f, " (no rule decided)"),
+        }
+    }
+}
+
+/// Run `frame` through the rules and capabilities of `net`
+pub fn evaluate(net: &RootInterface, frame: &Frame) -> Outcome {
+    let mut frame = frame.clone();
+    // members without a tag have the network's default
+    for t in &net.tags {
+        if let Some(d) = t.default {
+            frame.sender_tags.entry(t.id).or_insert(d);
+            frame.receiver_tags.entry(t.id).or_insert(d);
+        }
+    }
+    let mut copies = Vec::new();
+    if let Some((verdict, rule)) = run(&net.rules, &frame, &mut copies) {
+        return Outcome {
+            verdict,
+            decider: Some(Decider { capability: None, rule }),
+            copies,
+        };
+    }
+    for &id in &frame.sender_capabilities {
+        let cap = match net.capability(id) {
+            Some(c) => c,
+            None => continue,
+        };
+        match run(&cap.rules, &frame, &mut copies) {
+            Some((Verdict::Drop, _)) | None => continue,
+            Some((verdict, rule)) => {
+                return Outcome {
+                    verdict,
+                    decider: Some(Decider { capability: Some(id), rule }),
+                    copies,
+                }
+            }
+        }
+    }
+    Outcome {
+        verdict: Verdict::Drop,
+        decider: None,
+        copies,
+    }
+}
+
+/// One rule set, `None` when nothing decided (or it hit a `break`)
+fn run(rules: &[Rules], frame: &Frame, copies: &mut Vec<NodeAddress>) -> Option<(Verdict, usize)> {
+    let mut holds = true;
+    let mut n = 0;
+    for r in rules {
+        if !r.rule.is_action() {
+            let m = matches(&r.rule, frame) != r.rnot;
+            if r.ror {
+                holds |= m;
+            } else {
+                holds &= m;
+            }
+            continue;
+        }
+        n += 1;
+        if !holds {
+            holds = true;
+            continue;
+        }
+        match r.rule {
+            Rule::ActionDrop => return Some((Verdict::Drop, n)),
+            Rule::ActionAccept | Rule::ActionPriority { .. } => return Some((Verdict::Accept, n)),
+            Rule::ActionRedirect { address, .. } => return Some((Verdict::Redirect(address), n)),
+            Rule::ActionBreak => return None,
+            Rule::ActionTee { address, .. } | Rule::ActionWatch { address, .. } => copies.push(address),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn in_range<T: PartialOrd>(v: Option<T>, start: T, end: T) -> bool {
+    v.is_some_and(|v| v >= start && v <= end)
+}
+
+/// Both sides need the tag for a match on the two of them
+fn tags(frame: &Frame, id: u32, f: impl Fn(u32, u32) -> bool) -> bool {
+    match (frame.sender_tags.get(&id), frame.receiver_tags.get(&id)) {
+        (Some(&s), Some(&r)) => f(s, r),
+        _ => false,
+    }
+}
+
+fn same_mac(a: &Option<String>, b: &str) -> bool {
+    a.as_ref().is_some_and(|a| a.eq_ignore_ascii_case(b))
+}
+
+fn matches(rule: &Rule, frame: &Frame) -> bool {
+    let ipv4 = frame.ether_type == ZT_ETHERTYPE_IPV4;
+    let ipv6 = frame.ether_type == ZT_ETHERTYPE_IPV6;
+    match rule {
+        Rule::MatchSourceZerotierAddress { zt } => frame.zt_source == Some(*zt),
+        Rule::MatchDestZerotierAddress { zt } => frame.zt_dest == Some(*zt),
+        Rule::MatchVlanId { vlan_id } => frame.vlan_id == *vlan_id,
+        Rule::MatchVlanPcp { vlan_pcp } => frame.vlan_pcp == *vlan_pcp,
+        Rule::MatchVlanDei { vlan_dei } => frame.vlan_dei == *vlan_dei,
+        Rule::MatchMacSource { mac } => same_mac(&frame.mac_source, mac),
+        Rule::MatchMacDest { mac } => same_mac(&frame.mac_dest, mac),
+        Rule::MatchIpv4Source { ip } => ipv4 && frame.ip_source.is_some_and(|a| IpNet::from(*ip).contains(&a)),
+        Rule::MatchIpv4Dest { ip } => ipv4 && frame.ip_dest.is_some_and(|a| IpNet::from(*ip).contains(&a)),
+        Rule::MatchIpv6Source { ip } => ipv6 && frame.ip_source.is_some_and(|a| IpNet::from(*ip).contains(&a)),
+        Rule::MatchIpv6Dest { ip } => ipv6 && frame.ip_dest.is_some_and(|a| IpNet::from(*ip).contains(&a)),
+        Rule::MatchIpTos { mask, start, end } => in_range(frame.ip_tos.map(|t| t & mask), *start, *end),
+        Rule::MatchIpProtocol { ip_protocol } => frame.ip_protocol == Some(*ip_protocol),
+        Rule::MatchEthertype { ether_type } => frame.ether_type == *ether_type,
+        Rule::MatchIcmp { icmp_type, icmp_code } => match frame.icmp {
+            Some((t, c)) => t == *icmp_type && icmp_code.is_none_or(|code| c == code),
+            None => false,
+        },
+        Rule::MatchIpSourcePortRange { start, end } => in_range(frame.source_port, *start, *end),
+        Rule::MatchIpDestPortRange { start, end } => in_range(frame.dest_port, *start, *end),
+        Rule::MatchFrameSizeRange { start, end } => in_range(Some(frame.size), *start, *end),
+        Rule::MatchCharacteristics { mask } => frame.characteristics & mask != 0,
+        Rule::MatchRandom { probability } => frame.random <= *probability,
+        Rule::MatchTagsDifference { id, value } => {
+            tags(frame, *id, |s, r| (i64::from(s) - i64::from(r)).unsigned_abs() <= u64::from(*value))
+        }
+        Rule::MatchTagsBitwiseAnd { id, value } => tags(frame, *id, |s, r| s & r == *value),
+        Rule::MatchTagsBitwiseOr { id, value } => tags(frame, *id, |s, r| s | r == *value),
+        Rule::MatchTagsBitwiseXor { id, value } => tags(frame, *id, |s, r| s ^ r == *value),
+        Rule::MatchTagsEqual { id, value } => tags(frame, *id, |s, r| s == *value && r == *value),
+        Rule::MatchTagSender { id, value } => frame.sender_tags.get(id) == Some(value),
+        Rule::MatchTagReceiver { id, value } => frame.receiver_tags.get(id) == Some(value),
+        // integer ranges look into the payload, which we know nothing of
+        _ => false,
+    }
+}
+
+/// A test case: the frame, and what should become of it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    /// Where it starts in the cases file
+    pub line: usize,
+    pub verdict: Verdict,
+    /// Nodes that must get a copy, there may be more
+    pub copies: Vec<NodeAddress>,
+    pub frame: Frame,
+}
+
+impl Case {
+    pub fn passes(&self, outcome: &Outcome) -> bool {
+        self.verdict == outcome.verdict && self.copies.iter().all(|c| outcome.copies.contains(c))
+    }
+}
+
+/// Read test cases, with the tag and capability names of `compiled`
+pub fn parse_cases(source: &str, compiled: &CompiledRules) -> Result<Vec<Case>, RuleError> {
+    let tokens = lex(source);
+    let mut cases = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i].is(";") {
+            i += 1;
+            continue;
+        }
+        let end = match tokens[i..].iter().position(|t| t.is(";")) {
+            Some(e) => i + e,
+            None => return Err(error(&tokens[i], "test case is not closed with ;".to_owned())),
+        };
+        cases.push(case(&tokens[i..end], compiled)?);
+        i = end + 1;
+    }
+    Ok(cases)
+}
+
+/// Walks the words of one case
+struct Words<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Words<'a> {
+    fn next(&mut self, wanted: &str) -> Result<&'a Token, RuleError> {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.pos += 1;
+                Ok(t)
+            }
+            None => {
+                let last = &self.tokens[self.tokens.len() - 1];
+                Err(error(last, format!("expected {} after {}", wanted, last.text)))
+            }
+        }
+    }
+
+    fn number(&mut self, wanted: &str, max: u64) -> Result<u64, RuleError> {
+        parse_number(self.next(wanted)?, wanted, max)
+    }
+
+    fn parse<T: std::str::FromStr>(&mut self, wanted: &str) -> Result<T, RuleError> {
+        let t = self.next(wanted)?;
+        t.text
+            .parse()
+            .map_err(|_| error(t, format!("expected {}, got {}", wanted, t.text)))
+    }
+}
+
+fn case(tokens: &[Token], compiled: &CompiledRules) -> Result<Case, RuleError> {
+    let mut w = Words { tokens, pos: 0 };
+    let first = w.next("a verdict")?;
+    let verdict = match first.text.as_str() {
+        "accept" => Verdict::Accept,
+        "drop" => Verdict::Drop,
+        "redirect" => Verdict::Redirect(w.parse("a zerotier address")?),
+        _ => return Err(error(first, format!("expected accept, drop or redirect, got {}", first.text))),
+    };
+    let mut copies = Vec::new();
+    loop {
+        let t = w.next("when")?;
+        match t.text.as_str() {
+            "when" => break,
+            "tee" => copies.push(w.parse("a zerotier address")?),
+            _ => return Err(error(t, format!("expected tee or when, got {}", t.text))),
+        }
+    }
+
+    let mut f = Frame::default();
+    while w.pos < tokens.len() {
+        let t = w.next("a frame field")?;
+        match t.text.as_str() {
+            "and" => {}
+            "ethertype" => {
+                let e = w.next("an ethertype")?;
+                f.ether_type = match by_name(&ETHERTYPES, &e.text) {
+                    Some(e) => e,
+                    None => parse_number(e, "an ethertype", u64::from(u16::MAX))? as u16,
+                }
+            }
+            "ztsrc" => f.zt_source = Some(w.parse("a zerotier address")?),
+            "ztdest" => f.zt_dest = Some(w.parse("a zerotier address")?),
+            "macsrc" => f.mac_source = Some(w.next("a mac address")?.text.clone()),
+            "macdest" => f.mac_dest = Some(w.next("a mac address")?.text.clone()),
+            "ipsrc" => f.ip_source = Some(w.parse("an ip address")?),
+            "ipdest" => f.ip_dest = Some(w.parse("an ip address")?),
+            "iptos" => f.ip_tos = Some(w.number("a tos", 255)? as u8),
+            "ipprotocol" => {
+                let p = w.next("an ip protocol")?;
+                f.ip_protocol = Some(match by_name(&IP_PROTOCOLS, &p.text) {
+                    Some(p) => p,
+                    None => parse_number(p, "an ip protocol", 255)? as u8,
+                })
+            }
+            "icmp" => {
+                let icmp_type = w.number("an icmp type", 255)? as u8;
+                f.icmp = Some((icmp_type, w.number("an icmp code", 255)? as u8));
+            }
+            "sport" => f.source_port = Some(w.number("a port", 65535)? as u16),
+            "dport" => f.dest_port = Some(w.number("a port", 65535)? as u16),
+            "framesize" => f.size = w.number("a frame size", 65535)? as u16,
+            "vlan" => f.vlan_id = w.number("a vlan id", 4095)? as u16,
+            "vlanpcp" => f.vlan_pcp = w.number("a vlan pcp", 7)? as u8,
+            "vlandei" => f.vlan_dei = w.number("a vlan dei", 1)? as u8,
+            "chr" => loop {
+                let c = w.next("a characteristic")?;
+                match by_name(&CHARACTERISTICS, &c.text) {
+                    Some(bit) => f.characteristics |= 1 << bit,
+                    None => return Err(error(c, format!("unknown characteristic {}", c.text))),
+                }
+                match tokens.get(w.pos) {
+                    Some(c) if c.is(",") => w.pos += 1,
+                    _ => break,
+                }
+            },
+            "random" => f.random = w.number("a random number", u64::from(u32::MAX))? as u32,
+            "stag" | "rtag" => {
+                let name = w.next("a tag")?;
+                let tag = compiled.tags_by_name.get(&name.text);
+                let id = match tag {
+                    Some(tag) => tag.id,
+                    None => parse_number(name, "a tag id or name", u64::from(u32::MAX))? as u32,
+                };
+                let value = tag_value(w.next("a tag value")?, tag)?;
+                if t.is("stag") {
+                    f.sender_tags.insert(id, value);
+                } else {
+                    f.receiver_tags.insert(id, value);
+                }
+            }
+            "cap" => {
+                let name = w.next("a capability")?;
+                let id = match compiled.capabilities_by_name.get(&name.text) {
+                    Some(&id) => id,
+                    None => parse_number(name, "a capability id or name", u64::from(u32::MAX))? as u32,
+                };
+                f.sender_capabilities.push(id);
+            }
+            _ => return Err(error(t, format!("unknown frame field {}", t.text))),
+        }
+    }
+    Ok(Case {
+        line: first.line,
+        verdict,
+        copies,
+        frame: f,
+    })
+}
+
+/// Run every case against `net`, the outcome of each next to it
+pub fn run_cases<'a>(net: &RootInterface, cases: &'a [Case]) -> Vec<(&'a Case, Outcome)> {
+    cases.iter().map(|c| (c, evaluate(net, &c.frame))).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::rules::compile;
+    use super::*;
+
+    const POLICY: &str = "
+        tag role id 1000 enum 10 admin enum 20 user default 20;
+        drop not ethertype ipv4 and not ethertype arp and not ethertype ipv6;
+        tee 128 8056c2e21c ethertype arp;
+        accept ethertype arp;
+        cap ssh id 2000
+          drop dport 23;
+          accept ipprotocol tcp and dport 22;
+        ;
+        break chr tcp_syn and not chr tcp_ack;
+        accept teq role admin;
+        redirect deadbeef00 ipdest 10.9.0.0/16;
+        accept ipprotocol udp or ipprotocol icmp;
+    ";
+
+    fn outcome(cases: &str) -> Vec<String> {
+        let compiled = compile(POLICY).unwrap();
+        let cases = parse_cases(cases, &compiled).unwrap();
+        let mut net = RootInterface::default();
+        compiled.apply_to(&mut net);
+        run_cases(&net, &cases)
+            .into_iter()
+            .map(|(c, o)| format!("{} {}", c.passes(&o), o))
+            .collect()
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let got = outcome(
+            "drop when ethertype 0x88cc;
+             accept tee 8056c2e21c when ethertype arp;
+             drop when ethertype ipv4 ipprotocol tcp dport 22 chr tcp_syn;
+             accept when ethertype ipv4 ipprotocol tcp dport 22 chr tcp_syn cap ssh;
+             drop when ethertype ipv4 ipprotocol tcp dport 23 chr tcp_syn cap ssh;
+             accept when ethertype ipv4 ipprotocol tcp dport 23 stag role admin rtag role admin;
+             redirect deadbeef00 when ethertype ipv4 ipdest 10.9.1.1 ipprotocol tcp;
+             accept when ethertype ipv6 ipprotocol udp;
+             accept when ethertype ipv4 ipprotocol tcp;",
+        );
+        assert_eq!(
+            got,
+            vec![
+                "true drop (rule 1)",
+                "true accept tee 8056c2e21c (rule 3)",
+                "true drop (no rule decided)",
+                "true accept (rule 2 of cap 2000)",
+                "true drop (no rule decided)",
+                "true accept (rule 5)",
+                "true redirect deadbeef00 (rule 6)",
+                "true accept (rule 7)",
+                "false drop (no rule decided)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cases() {
+        let compiled = compile(POLICY).unwrap();
+        let cases = parse_cases("# ssh\naccept when\n  dport 22 cap ssh stag role user;", &compiled).unwrap();
+        assert_eq!(cases[0].line, 2);
+        assert_eq!(cases[0].frame.sender_capabilities, vec![2000]);
+        assert_eq!(cases[0].frame.sender_tags[&1000], 20);
+
+        let e = parse_cases("accept when dport 22;\ndrop when\n dport x;", &compiled).unwrap_err();
+        assert_eq!((e.line, e.column), (3, 8));
+        assert!(parse_cases("allow when dport 22;", &compiled).is_err());
+        assert!(parse_cases("accept dport 22;", &compiled).is_err());
+        assert!(parse_cases("accept when dport 22", &compiled).is_err());
+    }
+}
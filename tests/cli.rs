@@ -0,0 +1,239 @@
+//! Drive the ztproxy binary against the mock controller.
+//! Run with `cargo test --features mock`.
+#![cfg(feature = "mock")]
+
+extern crate failure;
+extern crate ztproxy;
+
+use failure::Error;
+use std::process::{Command, Output};
+use ztproxy::mock::MockController;
+use ztproxy::NetworkId;
+
+fn ztproxy(mock: &MockController, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ztproxy"))
+        .args(args)
+        .args(["--url", mock.base_url(), "--token", "sikrit"])
+        .output()
+        .unwrap()
+}
+
+const CREATE: [&str; 9] = ["create", "-n", "cli", "-s", "10.1.1.10", "-e", "10.1.1.20", "-m", "24"];
+
+/// A network with pool 10.1.1.10-10.1.1.20/24, its nwid as create printed it
+fn create(mock: &MockController) -> Result<String, Error> {
+    let out = ztproxy(mock, &CREATE);
+    assert!(out.status.success());
+    Ok(String::from_utf8(out.stdout)?.trim().to_owned())
+}
+
+#[test]
+fn test_create_auth_destroy() -> Result<(), Error> {
+    let mock = MockController::start("8056c2e21c", "sikrit")?;
+    let nwid = create(&mock)?;
+    assert!(nwid.starts_with("8056c2e21c"));
+    assert_eq!(nwid.len(), 16);
+    let id: NetworkId = nwid.parse()?;
+    assert_eq!(mock.client()?.get_network(id)?.rules.len(), 1);
+
+    mock.join(id, "deadbeef00".parse()?);
+    assert!(ztproxy(&mock, &["auth", "-i", &nwid, "-c", "deadbeef00"]).status.success());
+
+    // an authorized member keeps the net alive, unless forced
+    let out = ztproxy(&mock, &["destroy", "-i", &nwid]);
+    assert_eq!(out.status.code(), Some(201));
+    let out = ztproxy(&mock, &["destroy", "-i", &nwid, "--dry-run"]);
+    assert_eq!(out.status.code(), Some(201));
+    assert!(String::from_utf8(out.stderr)?.contains("would refuse: network still has 1 authorized members"));
+    assert!(ztproxy(&mock, &["destroy", "-i", &nwid, "--force", "--dry-run"]).status.success());
+    assert!(mock.client()?.get_network(id).is_ok());
+    assert!(ztproxy(&mock, &["destroy", "-i", &nwid, "--force"]).status.success());
+    assert!(mock.client()?.get_network(id).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_wrong_token() -> Result<(), Error> {
+    let mock = MockController::start("8056c2e21c", "other")?;
+    let out = ztproxy(&mock, &CREATE);
+    // 301 doesn't fit in an exit status, it exits as 241
+    assert_eq!(out.status.code(), Some(241));
+    assert!(String::from_utf8(out.stderr)?.contains("301"));
+    Ok(())
+}
+
+#[test]
+fn test_invalid_ids() -> Result<(), Error> {
+    let mock = MockController::start("8056c2e21c", "sikrit")?;
+    let out = ztproxy(&mock, &["auth", "-i", "8056c2e21c00001", "-c", "deadbeef00"]);
+    assert_eq!(out.status.code(), Some(142));
+    let out = ztproxy(&mock, &["auth", "-i", "8056c2e21c000001", "-c", "deadbeef"]);
+    assert_eq!(out.status.code(), Some(141));
+    let out = ztproxy(
+        &mock,
+        &["create", "-n", "cli", "-s", "10.1.1.10", "-e", "10.1.2.20", "-m", "24"],
+    );
+    assert_eq!(out.status.code(), Some(115));
+    let out = ztproxy(
+        &mock,
+        &["create", "-n", "cli", "-s", "10.1.1.1O", "-e", "10.1.1.20", "-m", "24"],
+    );
+    assert_eq!(out.status.code(), Some(117));
+    assert!(String::from_utf8(out.stderr)?.contains("invalid address 10.1.1.1O"));
+    let out = ztproxy(
+        &mock,
+        &["create", "-n", "cli", "-s", "10.1.1.1O", "-e", "10.1.1.20", "-m", "24", "--v6-assign", "7plane"],
+    );
+    // every problem is reported, the code is that of the first
+    assert_eq!(out.status.code(), Some(117));
+    assert!(String::from_utf8(out.stderr)?.contains("unknown assign mode 7plane"));
+    let nwid = "8056c2e21c000001";
+    let out = ztproxy(&mock, &["addsubnet", "-i", nwid, "-s", "fd00::1", "-e", "fd00::g", "-n", "64"]);
+    assert_eq!(out.status.code(), Some(117));
+    let out = ztproxy(&mock, &["addsubnet", "-i", nwid, "-s", "fd00::1", "-e", "fd00::f", "-n", "x"]);
+    assert_eq!(out.status.code(), Some(107));
+    let out = ztproxy(&mock, &["addroute", "-i", nwid, "-d", "172.22.2.0", "-n", "33", "-g", "10.1.1.1"]);
+    assert_eq!(out.status.code(), Some(107));
+    Ok(())
+}
+
+#[test]
+fn test_rules_set() -> Result<(), Error> {
+    let mock = MockController::start("8056c2e21c", "sikrit")?;
+    let nwid = mock.client()?.new_network(&Default::default())?.nwid.unwrap();
+    let dir = std::env::temp_dir().join(format!("ztproxy-rules-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let file = dir.join("policy.ztrules");
+    let file = file.to_str().unwrap();
+
+    std::fs::write(file, "drop not ethertype ipv4;\ncap ssh id 1 accept dport 22;\n;\naccept;\n")?;
+    let out = ztproxy(&mock, &["rules", "set", "-i", &nwid.to_string(), "-f", file]);
+    assert!(out.status.success());
+    let net = mock.client()?.get_network(nwid)?;
+    assert_eq!(net.rules.len(), 3);
+    assert_eq!(net.capabilities[0].id, 1);
+    mock.join(nwid, "deadbeef00".parse()?);
+    let grant = ["grant", "-i", &nwid.to_string(), "-c", "deadbeef00", "--cap", "1"];
+    assert!(ztproxy(&mock, &grant).status.success());
+    let out = ztproxy(&mock, &[&grant[..5], &["--cap", "2"]].concat());
+    assert_eq!(out.status.code(), Some(161));
+    let out = ztproxy(&mock, &[&grant[..5], &["--cap", "two"]].concat());
+    assert_eq!(out.status.code(), Some(207));
+    let out = ztproxy(&mock, &[&grant[..5], &["--tag", "1000=one"]].concat());
+    assert_eq!(out.status.code(), Some(203));
+    let out = ztproxy(&mock, &["rules", "show", "-i", &nwid.to_string()]);
+    assert!(out.status.success());
+    let shown = String::from_utf8(out.stdout)?;
+    assert!(shown.contains("cap cap_1\n  id 1\n  accept dport 22;\n;\n"));
+    assert!(shown.ends_with("drop not ethertype ipv4;\naccept;\n"));
+
+    std::fs::write(file, "accept\n  dport 22-;\n")?;
+    let out = ztproxy(&mock, &["rules", "set", "-i", &nwid.to_string(), "-f", file]);
+    assert_eq!(out.status.code(), Some(204));
+    assert!(String::from_utf8(out.stderr)?.contains("policy.ztrules:2:9"));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_rules_test() -> Result<(), Error> {
+    let dir = std::env::temp_dir().join(format!("ztproxy-cases-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let (rules, cases) = (dir.join("p.ztrules"), dir.join("p.cases"));
+    std::fs::write(&rules, "cap ssh id 1 accept dport 22;\n;\naccept ethertype arp;\n")?;
+    std::fs::write(&cases, "accept when ethertype arp;\naccept when dport 22 cap ssh;\n")?;
+    let run = || {
+        Command::new(env!("CARGO_BIN_EXE_ztproxy"))
+            .args(["rules", "test", "-f", rules.to_str().unwrap(), "-c", cases.to_str().unwrap()])
+            .output()
+            .unwrap()
+    };
+    let out = run();
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout)?.contains("p.cases:2: accept (rule 1 of cap 1)"));
+
+    std::fs::write(&cases, "accept when ethertype arp;\naccept when dport 22;\n")?;
+    let out = run();
+    assert_eq!(out.status.code(), Some(206));
+    assert!(String::from_utf8(out.stdout)?.contains("FAIL"));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_create_policy() -> Result<(), Error> {
+    let mock = MockController::start("8056c2e21c", "sikrit")?;
+    let out = ztproxy(
+        &mock,
+        &[&CREATE[..], &["--policy", "admin-capability", "--v6-assign", "6plane"]].concat(),
+    );
+    assert!(out.status.success());
+    let net = mock.client()?.get_network(String::from_utf8(out.stdout)?.trim().parse()?)?;
+    assert_eq!(net.rules.len(), 8);
+    assert_eq!(net.capabilities[0].id, 1000);
+    assert!(net.v6_assign_mode.sixplane && net.v4_assign_mode.zt);
+    assert!(!ztproxy(&mock, &[&CREATE[..], &["--policy", "open"]].concat()).status.success());
+    Ok(())
+}
+
+#[test]
+fn test_ip() -> Result<(), Error> {
+    let mock = MockController::start("8056c2e21c", "sikrit")?;
+    let nwid = create(&mock)?;
+    mock.join(nwid.parse()?, "deadbeef00".parse()?);
+    let member = ["-i", &nwid, "-c", "deadbeef00"];
+
+    let out = ztproxy(&mock, &[&["ip", "assign"][..], &member].concat());
+    assert_eq!(String::from_utf8(out.stdout)?.trim(), "10.1.1.10");
+    let out = ztproxy(&mock, &[&["ip", "assign"][..], &member, &["-a", "10.1.1.10"]].concat());
+    assert_eq!(out.status.code(), Some(183));
+    let out = ztproxy(&mock, &["ip", "list", "-i", &nwid]);
+    let listed = String::from_utf8(out.stdout)?;
+    assert!(listed.contains("pool 0: 10.1.1.10-10.1.1.20 1/11 used"));
+    assert!(listed.contains("10.1.1.10 deadbeef00"));
+
+    let out = ztproxy(&mock, &[&["ip", "predict"][..], &member].concat());
+    assert!(String::from_utf8(out.stdout)?.contains("6plane  fc"));
+
+    let release = [&["ip", "release"][..], &member, &["-a", "10.1.1.10"]].concat();
+    assert!(ztproxy(&mock, &release).status.success());
+    assert_eq!(ztproxy(&mock, &release).status.code(), Some(184));
+
+    let out = ztproxy(&mock, &[&["ip", "assign"][..], &member, &["-a", "10.1.1"]].concat());
+    assert_eq!(out.status.code(), Some(117));
+    let out = ztproxy(&mock, &[&["ip", "release"][..], &member, &["-a", "10.1.1"]].concat());
+    assert_eq!(out.status.code(), Some(117));
+    Ok(())
+}
+
+#[test]
+fn test_set() -> Result<(), Error> {
+    let mock = MockController::start("8056c2e21c", "sikrit")?;
+    let nwid = create(&mock)?;
+
+    let out = ztproxy(&mock, &["set", "-i", &nwid, "--mtu", "1000"]);
+    assert_eq!(out.status.code(), Some(120));
+    assert_eq!(ztproxy(&mock, &["set", "-i", &nwid, "--mtu", "big"]).status.code(), Some(208));
+    let out = ztproxy(&mock, &["set", "-i", &nwid, "--multicast-limit", "lots"]);
+    assert_eq!(out.status.code(), Some(209));
+    let out = ztproxy(&mock, &["set", "-i", &nwid, "--dns-server", "10.1.1"]);
+    assert_eq!(out.status.code(), Some(117));
+    let out = ztproxy(
+        &mock,
+        &[
+            "set", "-i", &nwid, "--mtu", "1400", "--broadcast", "false",
+            "--dns-domain", "zt.example", "--dns-server", "10.1.1.1", "--dns-server", "fd00::1",
+        ],
+    );
+    assert!(out.status.success());
+    let net = mock.client()?.get_network(nwid.parse()?)?;
+    assert_eq!((net.mtu, net.multicast_limit), (1400, 32));
+    assert!(!net.enable_broadcast);
+    assert_eq!(net.dns.domain, "zt.example");
+    assert_eq!(net.dns.servers.len(), 2);
+
+    assert!(ztproxy(&mock, &["set", "-i", &nwid, "--no-dns"]).status.success());
+    let net = mock.client()?.get_network(nwid.parse()?)?;
+    assert!(net.dns.servers.is_empty() && net.mtu == 1400);
+    Ok(())
+}